@@ -0,0 +1,55 @@
+//! Compares encrypting a large tree of small files with a new gpgme session
+//! per file against one session that is reused for all of them, as done by
+//! `GpgSync`.
+//!
+//! Needs a working GnuPG installation, run with `cargo bench`.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+use gpgsync::{CryptoBackend, EncryptionPolicy, GpgmeBackend};
+
+/// Number of files in the synthetic tree.
+const TREE_SIZE: usize = 1000;
+
+/// Contents of small notes, like those of an org-roam directory.
+fn synthetic_tree() -> Vec<Vec<u8>> {
+    (0..TREE_SIZE)
+        .map(|i| format!("#+title: Note {}\n\n{}\n", i, "Some text. ".repeat(i % 50)).into_bytes())
+        .collect()
+}
+
+fn encrypt_tree(c: &mut Criterion) {
+    let tree = synthetic_tree();
+    let policy = EncryptionPolicy::Passphrase("bench".to_string().into());
+
+    let mut group = c.benchmark_group("encrypt_tree");
+    group.sample_size(10);
+
+    group.bench_function(BenchmarkId::new("session_per_file", TREE_SIZE), |b| {
+        b.iter(|| {
+            for plaintext in &tree {
+                let mut ciphertext = Vec::new();
+                GpgmeBackend::new()
+                    .encrypt(&mut &plaintext[..], &mut ciphertext, &policy)
+                    .unwrap();
+            }
+        })
+    });
+
+    group.bench_function(BenchmarkId::new("shared_session", TREE_SIZE), |b| {
+        let backend = GpgmeBackend::new();
+        b.iter(|| {
+            for plaintext in &tree {
+                let mut ciphertext = Vec::new();
+                backend
+                    .encrypt(&mut &plaintext[..], &mut ciphertext, &policy)
+                    .unwrap();
+            }
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, encrypt_tree);
+criterion_main!(benches);
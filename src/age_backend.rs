@@ -0,0 +1,151 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::iter;
+use std::path::PathBuf;
+
+use age::secrecy::SecretString;
+
+use crate::backend::CryptoBackend;
+use crate::policy::EncryptionPolicy;
+
+/// Encryption with age.  Produces `.age` files.
+///
+/// Passphrases are used with age's scrypt mode, recipients are X25519 public
+/// keys (`age1...`).  Files encrypted to recipients are decrypted with the
+/// identities (secret keys) the backend was created with.
+pub struct AgeBackend {
+    identities: Vec<age::x25519::Identity>,
+}
+
+impl AgeBackend {
+    /// Returns a backend without any identities, it can only decrypt files
+    /// encrypted with a passphrase.
+    pub fn new() -> Self {
+        Self {
+            identities: Vec::new(),
+        }
+    }
+
+    /// Returns a backend that decrypts with the identities contained in the
+    /// given identity files, as written by `age-keygen`.
+    pub fn with_identity_files(identity_files: &[PathBuf]) -> io::Result<Self> {
+        let mut identities = Vec::new();
+        for p in identity_files {
+            for line in BufReader::new(std::fs::File::open(p)?).lines() {
+                let line = line?;
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                identities.push(
+                    line.parse::<age::x25519::Identity>()
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+                );
+            }
+        }
+        Ok(Self { identities })
+    }
+}
+
+impl Default for AgeBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CryptoBackend for AgeBackend {
+    fn encrypt(
+        &self,
+        plaintext_in: &mut (dyn Read + Send),
+        ciphertext_out: &mut (dyn Write + Send),
+        policy: &EncryptionPolicy,
+    ) -> io::Result<()> {
+        let encryptor = match policy {
+            EncryptionPolicy::Passphrase(passphrase) => age::Encryptor::with_user_passphrase(
+                SecretString::from(passphrase.expose_str().to_owned()),
+            ),
+            EncryptionPolicy::Recipients(recipients) => {
+                let recipients = recipients
+                    .iter()
+                    .map(|r| {
+                        r.parse::<age::x25519::Recipient>()
+                            .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+                    })
+                    .collect::<io::Result<Vec<_>>>()?;
+                age::Encryptor::with_recipients(recipients.iter().map(|r| r as &dyn age::Recipient))
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?
+            }
+        };
+
+        let mut writer = encryptor.wrap_output(ciphertext_out)?;
+        io::copy(plaintext_in, &mut writer)?;
+        writer.finish()?;
+
+        Ok(())
+    }
+
+    fn decrypt(
+        &self,
+        ciphertext_in: &mut (dyn Read + Send),
+        plaintext_out: &mut (dyn Write + Send),
+        policy: &EncryptionPolicy,
+    ) -> io::Result<()> {
+        let decryptor = age::Decryptor::new(ciphertext_in)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let mut reader = match policy {
+            EncryptionPolicy::Passphrase(passphrase) => {
+                let identity = age::scrypt::Identity::new(SecretString::from(
+                    passphrase.expose_str().to_owned(),
+                ));
+                decryptor.decrypt(iter::once(&identity as &dyn age::Identity))
+            }
+            EncryptionPolicy::Recipients(_) => {
+                decryptor.decrypt(self.identities.iter().map(|i| i as &dyn age::Identity))
+            }
+        }
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        io::copy(&mut reader, plaintext_out)?;
+
+        Ok(())
+    }
+
+    fn extension(&self) -> &'static str {
+        "age"
+    }
+
+    fn is_ciphertext(&self, header: &[u8]) -> bool {
+        header.starts_with(b"age-encryption.org/v1\n")
+            || header.starts_with(b"-----BEGIN AGE ENCRYPTED FILE-----")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AgeBackend;
+    use crate::backend::CryptoBackend;
+    use crate::policy::EncryptionPolicy;
+
+    #[test]
+    fn test_roundtrip_passphrase() {
+        let backend = AgeBackend::new();
+        let policy = EncryptionPolicy::Passphrase("test".to_string().into());
+
+        let mut ciphertext = Vec::new();
+        backend
+            .encrypt(&mut &b"hello"[..], &mut ciphertext, &policy)
+            .unwrap();
+        assert!(backend.is_ciphertext(&ciphertext));
+
+        let mut plaintext = Vec::new();
+        backend
+            .decrypt(&mut &ciphertext[..], &mut plaintext, &policy)
+            .unwrap();
+        assert_eq!(plaintext, b"hello");
+
+        let wrong_policy = EncryptionPolicy::Passphrase("wrong".to_string().into());
+        assert!(backend
+            .decrypt(&mut &ciphertext[..], &mut Vec::new(), &wrong_policy)
+            .is_err());
+    }
+}
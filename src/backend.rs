@@ -0,0 +1,89 @@
+use std::io::{self, Read, Write};
+
+use crate::policy::EncryptionPolicy;
+
+/// An implementation of the en- and decryption of file contents.
+///
+/// The sync logic only ever talks to the crypto through this trait, so that
+/// different encryption tools can be used for the gpg root.  Files are en- and
+/// decrypted from several threads at once.
+pub trait CryptoBackend: Send + Sync {
+    /// Reads all of `plaintext_in` and writes it, encrypted according to
+    /// `policy`, to `ciphertext_out`.
+    fn encrypt(
+        &self,
+        plaintext_in: &mut (dyn Read + Send),
+        ciphertext_out: &mut (dyn Write + Send),
+        policy: &EncryptionPolicy,
+    ) -> io::Result<()>;
+
+    /// Reads all of `ciphertext_in` and writes the decrypted contents to
+    /// `plaintext_out`.  The `policy` provides the secrets needed for it.
+    fn decrypt(
+        &self,
+        ciphertext_in: &mut (dyn Read + Send),
+        plaintext_out: &mut (dyn Write + Send),
+        policy: &EncryptionPolicy,
+    ) -> io::Result<()>;
+
+    /// The file extension of encrypted files, without the leading dot.
+    fn extension(&self) -> &'static str;
+
+    /// Returns whether `header`, the first bytes of a file, look like a
+    /// ciphertext produced by this backend.
+    fn is_ciphertext(&self, header: &[u8]) -> bool;
+}
+
+impl<B: CryptoBackend + ?Sized> CryptoBackend for Box<B> {
+    fn encrypt(
+        &self,
+        plaintext_in: &mut (dyn Read + Send),
+        ciphertext_out: &mut (dyn Write + Send),
+        policy: &EncryptionPolicy,
+    ) -> io::Result<()> {
+        (**self).encrypt(plaintext_in, ciphertext_out, policy)
+    }
+
+    fn decrypt(
+        &self,
+        ciphertext_in: &mut (dyn Read + Send),
+        plaintext_out: &mut (dyn Write + Send),
+        policy: &EncryptionPolicy,
+    ) -> io::Result<()> {
+        (**self).decrypt(ciphertext_in, plaintext_out, policy)
+    }
+
+    fn extension(&self) -> &'static str {
+        (**self).extension()
+    }
+
+    fn is_ciphertext(&self, header: &[u8]) -> bool {
+        (**self).is_ciphertext(header)
+    }
+}
+
+/// Returns whether `header` is the beginning of an encrypted OpenPGP message.
+///
+/// Binary messages start with a public-key (tag 1) or symmetric-key (tag 3)
+/// encrypted session key packet, in either the old or the new packet format.
+#[cfg(any(feature = "gpgme", feature = "pgp"))]
+pub(crate) fn is_openpgp_message(header: &[u8]) -> bool {
+    if header.starts_with(b"-----BEGIN PGP MESSAGE-----") {
+        return true;
+    }
+    match header.first() {
+        Some(&b) if b & 0xc0 == 0xc0 => matches!(b & 0x3f, 1 | 3),
+        Some(&b) if b & 0x80 == 0x80 => matches!((b >> 2) & 0x0f, 1 | 3),
+        _ => false,
+    }
+}
+
+#[cfg(all(test, any(feature = "gpgme", feature = "pgp")))]
+mod test {
+    #[test]
+    fn test_is_openpgp_message() {
+        assert!(super::is_openpgp_message(include_bytes!("notes.txt.gpg")));
+        assert!(!super::is_openpgp_message(b"hello"));
+        assert!(!super::is_openpgp_message(b""));
+    }
+}
@@ -0,0 +1,99 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::backend::CryptoBackend;
+use crate::filesync::FileStatus;
+use crate::fileutils;
+use crate::policy::EncryptionPolicy;
+use crate::syncentity::SyncEntity;
+
+/// A sync entity whose plain and gpg versions were both changed to different
+/// contents.
+///
+/// The gpg version is kept as a conflict copy next to the plain file.  The
+/// conflict is resolved by the user deleting the conflict copy, after merging
+/// what they want to keep into the plain file.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Conflict {
+    /// Path of the conflict copy, relative to the plain root.
+    pub copy: PathBuf,
+    /// Status of the gpg file when the conflict was found.
+    pub gpg_status: FileStatus,
+}
+
+/// Returns the path of the `n`th conflict copy of `rel` created at `date`, e.
+/// g. "notes (conflict from gpg 2026-10-18 1402).txt".
+fn copy_path(rel: &Path, date: &str, n: usize) -> PathBuf {
+    let mut name = rel.file_stem().unwrap().to_owned();
+    name.push(" (conflict from gpg ");
+    name.push(date);
+    if n > 1 {
+        name.push(format!(" {}", n));
+    }
+    name.push(")");
+    if let Some(extension) = rel.extension() {
+        name.push(".");
+        name.push(extension);
+    }
+    rel.with_file_name(name)
+}
+
+/// Decrypts the gpg version of `se` to a new conflict copy next to the plain
+/// file and notifies the user.  Returns the conflict.
+pub fn create_copy(
+    se: &SyncEntity,
+    backend: &dyn CryptoBackend,
+    policy: &EncryptionPolicy,
+) -> io::Result<Conflict> {
+    let date = chrono::Local::now().format("%Y-%m-%d %H%M").to_string();
+    let copy = (1..)
+        .map(|n| SyncEntity::from_rel(&copy_path(se.rel_without_gpg(), &date, n), se.roots()))
+        .find(|copy| !copy.as_plain().exists())
+        .unwrap();
+
+    let gpg_status = fileutils::file_status(&se.as_gpg())?;
+    let mut gpg_f = fileutils::open_read(&se.as_gpg())?;
+    fileutils::write_atomically(&copy.as_plain(), |f| backend.decrypt(&mut gpg_f, f, policy))?;
+
+    println!("conflict {:?}, saved the gpg version to {:?}", &se, &copy);
+    notify(se.rel_without_gpg(), copy.rel_without_gpg());
+
+    Ok(Conflict {
+        copy: copy.rel_without_gpg().clone(),
+        gpg_status,
+    })
+}
+
+fn notify(rel: &Path, copy: &Path) {
+    let result = notify_rust::Notification::new()
+        .summary("GPGSync conflict")
+        .body(&format!(
+            "{:?} was changed on both sides.  The gpg version was saved to {:?}, delete it once you have merged the changes.",
+            rel, copy
+        ))
+        .timeout(10000)
+        .show();
+    if let Err(e) = result {
+        println!("could not show notification: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::copy_path;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_copy_path() {
+        assert_eq!(
+            copy_path(Path::new("dir/notes.txt"), "2026-10-18 1402", 1),
+            PathBuf::from("dir/notes (conflict from gpg 2026-10-18 1402).txt")
+        );
+        assert_eq!(
+            copy_path(Path::new("Makefile"), "2026-10-18 1402", 2),
+            PathBuf::from("Makefile (conflict from gpg 2026-10-18 1402 2)")
+        );
+    }
+}
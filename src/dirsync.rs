@@ -0,0 +1,63 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::filesync::{self, SyncAction};
+use crate::syncdb::SyncDb;
+use crate::syncentity::SyncRoots;
+
+/// Syncs the directories at the relative paths `rels` and records the result
+/// in the database.
+///
+/// Subdirectories are synced before their parents, so that a deleted
+/// directory tree can be removed from the bottom up.  The files in the
+/// directories must have been synced before.
+pub fn sync_dirs(
+    rels: impl IntoIterator<Item = PathBuf>,
+    db: &mut SyncDb,
+    roots: &SyncRoots,
+) -> io::Result<()> {
+    let mut rels = rels.into_iter().collect::<Vec<_>>();
+    rels.sort();
+    rels.dedup();
+    rels.sort_by_key(|rel| std::cmp::Reverse(rel.components().count()));
+
+    for rel in rels {
+        let plain = roots.plain_root.join(&rel);
+        let gpg = roots.gpg_path(&rel, false);
+
+        let sync_action = filesync::determine_dir_sync_action(
+            db.is_dir_synced(&rel),
+            plain.is_dir(),
+            gpg.is_dir(),
+        );
+        if sync_action != SyncAction::None {
+            println!("directory {:?} {:?}", &rel, sync_action);
+        }
+        match sync_action {
+            SyncAction::None | SyncAction::PossibleConflict => {}
+            SyncAction::PushPlain => {
+                roots.store_long_names(&rel, false)?;
+                std::fs::create_dir_all(&gpg)?
+            }
+            SyncAction::PushGpg => std::fs::create_dir_all(&plain)?,
+            SyncAction::DeletePlain => remove_dir_or_restore(&plain, &gpg)?,
+            SyncAction::DeleteGpg => remove_dir_or_restore(&gpg, &plain)?,
+        }
+
+        db.set_dir_synced(&rel, plain.is_dir() && gpg.is_dir());
+    }
+
+    Ok(())
+}
+
+/// Removes the directory `dir`, whose counterpart `deleted` was deleted, if it
+/// is empty.  Otherwise it still holds files that were changed since, and the
+/// counterpart is created again for them.
+fn remove_dir_or_restore(dir: &Path, deleted: &Path) -> io::Result<()> {
+    if std::fs::read_dir(dir)?.next().is_none() {
+        std::fs::remove_dir(dir)
+    } else {
+        println!("{:?} is not empty, not deleting it", dir);
+        std::fs::create_dir_all(deleted)
+    }
+}
@@ -0,0 +1,188 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+
+use crate::{fileutils, hidden};
+
+/// File names of the exclude files, which can be put into any directory of the
+/// plain root.  If both exist in a directory, the rules of the latter win.
+pub const EXCLUDE_FILENAMES: [&str; 2] = [".gitignore", ".gpgsyncignore"];
+
+/// Path of git's repository-wide exclude file, relative to the plain root.
+const GIT_EXCLUDE_PATH: &str = ".git/info/exclude";
+
+/// Decides which paths are excluded from the sync, following the rules of
+/// gitignore files.
+///
+/// The rules come from the `--exclude` globs, the exclude files of the
+/// directories containing a path, and `.git/info/exclude`, in this order of
+/// precedence.  Among the exclude files, the deepest one wins.  As with git,
+/// everything in an excluded directory is excluded, and rules starting with
+/// `!` include paths again.
+///
+/// The paths are relative to the plain root.  Files in the gpg root are
+/// matched by their plain path, so that both sides are excluded alike.
+pub struct Excludes {
+    plain_root: PathBuf,
+    globs: Vec<String>,
+    cli: Gitignore,
+    dirs: HashMap<PathBuf, Gitignore>,
+    git_exclude: Gitignore,
+}
+
+impl Excludes {
+    /// Reads all exclude files in `plain_root`.  `globs` are gitignore rules
+    /// that apply to the whole plain root, e. g. from the command line.
+    pub fn load(plain_root: &Path, globs: &[String]) -> anyhow::Result<Self> {
+        let mut builder = GitignoreBuilder::new(plain_root);
+        for glob in globs {
+            builder
+                .add_line(None, glob)
+                .with_context(|| format!("invalid exclude glob {:?}", glob))?;
+        }
+        let cli = builder.build()?;
+
+        let mut dirs = HashMap::new();
+        let mut exclude_files = Vec::new();
+        fileutils::visit_dir(plain_root, &mut |de| {
+            let rel = de.path().strip_prefix(plain_root).unwrap().to_path_buf();
+            let dir = rel.parent().unwrap();
+            if !hidden::is_denied(dir)
+                && EXCLUDE_FILENAMES.iter().any(|name| de.file_name() == *name)
+            {
+                exclude_files.push(dir.to_path_buf());
+            }
+        })?;
+        exclude_files.sort();
+        exclude_files.dedup();
+        for dir in exclude_files {
+            let abs_dir = plain_root.join(&dir);
+            let paths = EXCLUDE_FILENAMES.iter().map(|name| abs_dir.join(name));
+            dirs.insert(dir, build_gitignore(&abs_dir, paths)?);
+        }
+
+        let git_exclude = build_gitignore(
+            plain_root,
+            std::iter::once(plain_root.join(GIT_EXCLUDE_PATH)),
+        )?;
+
+        Ok(Self {
+            plain_root: plain_root.to_path_buf(),
+            globs: globs.to_vec(),
+            cli,
+            dirs,
+            git_exclude,
+        })
+    }
+
+    /// Reads the exclude files again, after one of them changed.
+    pub fn reload(&mut self) -> anyhow::Result<()> {
+        *self = Self::load(&self.plain_root, &self.globs)?;
+        Ok(())
+    }
+
+    /// Returns whether `p` is an exclude file, whose changes require a reload.
+    pub fn is_exclude_file(&self, p: &Path) -> bool {
+        p.starts_with(&self.plain_root)
+            && (p == self.plain_root.join(GIT_EXCLUDE_PATH)
+                || EXCLUDE_FILENAMES
+                    .iter()
+                    .any(|name| p.file_name() == Some(name.as_ref())))
+    }
+
+    /// Returns whether the file or directory at the relative path `rel` is
+    /// excluded, either itself or by one of its parent directories.
+    pub fn is_excluded(&self, rel: &Path, is_dir: bool) -> bool {
+        let mut prefix = PathBuf::new();
+        let mut components = rel.components().peekable();
+        while let Some(component) = components.next() {
+            prefix.push(component);
+            if self.matches(&prefix, is_dir || components.peek().is_some()) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Returns whether the rule with the highest precedence that matches
+    /// `rel` itself excludes it.
+    fn matches(&self, rel: &Path, is_dir: bool) -> bool {
+        let path = self.plain_root.join(rel);
+        let dirs = rel.ancestors().skip(1).filter_map(|dir| self.dirs.get(dir));
+        let gitignores = std::iter::once(&self.cli)
+            .chain(dirs)
+            .chain(std::iter::once(&self.git_exclude));
+        for gitignore in gitignores {
+            match gitignore.matched(&path, is_dir) {
+                Match::Ignore(_) => return true,
+                Match::Whitelist(_) => return false,
+                Match::None => {}
+            }
+        }
+        false
+    }
+}
+
+/// Builds the matcher for the rules in those of the exclude files at `paths`
+/// that exist, which apply below `dir`.  Invalid rules are skipped.
+fn build_gitignore(dir: &Path, paths: impl Iterator<Item = PathBuf>) -> anyhow::Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new(dir);
+    for path in paths {
+        if path.is_file() {
+            if let Some(e) = builder.add(&path) {
+                println!("error in exclude file {:?}: {}", path, e);
+            }
+        }
+    }
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod test {
+    use super::Excludes;
+    use std::path::Path;
+
+    #[test]
+    fn test_excludes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = &std::fs::canonicalize(tmp.path()).unwrap();
+        std::fs::create_dir_all(dir.join("sub/deeper")).unwrap();
+        std::fs::create_dir_all(dir.join(".git/info")).unwrap();
+        std::fs::write(dir.join(".gitignore"), "*.log\nbuild/\n/top\n").unwrap();
+        std::fs::write(dir.join("sub/.gitignore"), "!keep.log\nlocal\n").unwrap();
+        std::fs::write(dir.join("sub/.gpgsyncignore"), "keep.log\n").unwrap();
+        std::fs::write(dir.join("sub/deeper/.gpgsyncignore"), "!keep.log\n").unwrap();
+        std::fs::write(dir.join(".git/info/exclude"), "secret\n").unwrap();
+
+        let excludes = Excludes::load(dir, &["*.tmp".to_string()]).unwrap();
+        let excluded = |rel: &str, is_dir| excludes.is_excluded(Path::new(rel), is_dir);
+
+        assert!(!excluded("notes.txt", false));
+        assert!(excluded("a.log", false));
+        assert!(excluded("sub/a.log", false));
+        assert!(excluded("x.tmp", false));
+        assert!(excluded("sub/secret", false));
+
+        // directory rules, also applying to everything in the directory
+        assert!(!excluded("build", false));
+        assert!(excluded("build", true));
+        assert!(excluded("sub/build/a.txt", false));
+
+        // anchored rules only apply in their own directory
+        assert!(excluded("top", false));
+        assert!(!excluded("sub/top", false));
+
+        // nested exclude files, the deeper and the .gpgsyncignore win
+        assert!(excluded("sub/local", false));
+        assert!(!excluded("local", false));
+        assert!(excluded("sub/keep.log", false));
+        assert!(!excluded("sub/deeper/keep.log", false));
+
+        assert!(excludes.is_exclude_file(&dir.join("sub/.gpgsyncignore")));
+        assert!(excludes.is_exclude_file(&dir.join(".git/info/exclude")));
+        assert!(!excludes.is_exclude_file(&dir.join("notes.txt")));
+    }
+}
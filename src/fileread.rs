@@ -1,5 +1,7 @@
 use async_std::prelude::*;
 
+// only used by the test so far
+#[allow(dead_code)]
 async fn r<F, O>(
     file_path: &std::path::Path,
     delay: std::time::Duration,
@@ -18,7 +20,7 @@ where
 
     let after_mtime = std::fs::metadata(file_path)?.modified()?;
     if after_mtime != orig_mtime {
-        return Err(std::io::Error::new(std::io::ErrorKind::Other, "oh no"));
+        return Err(std::io::Error::other("oh no"));
     }
 
     Ok(i)
@@ -27,15 +29,12 @@ where
 #[cfg(test)]
 mod test {
     use super::*;
-    use std::io::Write;
 
     #[test]
     fn test_fileread() -> std::io::Result<()> {
-        {
-            let mut file = std::fs::File::create("foo.txt")?;
-        }
+        std::fs::File::create("foo.txt")?;
         let writer = async_std::task::spawn(async {
-            for c in 'a'..'z' {
+            for c in 'a'..='y' {
                 {
                     let mut file = async_std::fs::File::create("foo.txt").await.unwrap();
                     let st = String::from(c);
@@ -60,10 +59,7 @@ mod test {
                         let mut s = String::new();
                         rd.read_to_string(&mut s).await.unwrap();
                         //println!("read: {}", s);
-                        let fs = s
-                            .chars()
-                            .next()
-                            .ok_or(std::io::Error::new(std::io::ErrorKind::Other, "no first"))?;
+                        let fs = s.chars().next().ok_or(std::io::Error::other("no first"))?;
                         assert!(s.chars().all(|ch| ch == fs));
 
                         Ok(fs)
@@ -80,7 +76,7 @@ mod test {
                     c = i;
                     println!("set to {}", i)
                 }
-                Err(e) => panic!(e),
+                Err(e) => panic!("{}", e),
             }
         }
 
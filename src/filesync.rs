@@ -1,10 +1,99 @@
+use std::io;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use serde::{Deserialize, Serialize};
 
-#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+/// Filesystems with whole-second modification times have a resolution of up
+/// to two seconds (FAT).
+const COARSE_MTIME_RESOLUTION: Duration = Duration::from_secs(2);
+
+/// What is known about an existing file.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FileMeta {
+    pub size: u64,
+    pub mtime: SystemTime,
+    pub inode: u64,
+    /// SHA-256 hash of the plaintext, if known.  Gpg files are not decrypted
+    /// just to find out, their hash is only known after a sync.
+    pub hash: Option<Vec<u8>>,
+    /// When the metadata were read.
+    pub checked: SystemTime,
+}
+
+impl FileMeta {
+    fn same_metadata(&self, other: &FileMeta) -> bool {
+        self.size == other.size && self.mtime == other.mtime && self.inode == other.inode
+    }
+
+    /// Returns whether the file may have been modified after it was checked
+    /// without its mtime changing.  That is the case on filesystems with a
+    /// coarse mtime resolution, if it was checked shortly after its mtime.
+    fn is_racy(&self) -> bool {
+        let coarse = self
+            .mtime
+            .duration_since(UNIX_EPOCH)
+            .is_ok_and(|d| d.subsec_nanos() == 0);
+        coarse && self.checked < self.mtime + COARSE_MTIME_RESOLUTION
+    }
+
+    /// Returns whether the file described by `cur` still has the contents it
+    /// had when `self` was recorded.  The hashes are compared if both are
+    /// known, the metadata otherwise.
+    fn unchanged(&self, cur: &FileMeta) -> bool {
+        match (&self.hash, &cur.hash) {
+            (Some(prev), Some(cur)) => prev == cur,
+            _ => self.same_metadata(cur) && !self.is_racy(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum FileStatus {
     Nonexistent,
-    Existent(std::time::SystemTime), //, Option<Revision>),
-                                     // Conflicted,
+    Existent(FileMeta),
+}
+
+impl FileStatus {
+    pub fn hash(&self) -> Option<&Vec<u8>> {
+        match self {
+            FileStatus::Nonexistent => None,
+            FileStatus::Existent(meta) => meta.hash.as_ref(),
+        }
+    }
+
+    /// Returns whether both statuses are of the same file with the same size
+    /// and mtime, or both of a missing file.
+    pub fn same_metadata(&self, other: &FileStatus) -> bool {
+        match (self, other) {
+            (FileStatus::Nonexistent, FileStatus::Nonexistent) => true,
+            (FileStatus::Existent(a), FileStatus::Existent(b)) => a.same_metadata(b),
+            _ => false,
+        }
+    }
+
+    /// Fills in the hash of the file.  The hash of `prev` is taken over if
+    /// the metadata show that the file is unmodified since, `hash` is called
+    /// otherwise.
+    pub fn with_hash(
+        self,
+        prev: &FileStatus,
+        hash: impl FnOnce() -> io::Result<Option<Vec<u8>>>,
+    ) -> io::Result<FileStatus> {
+        match self {
+            FileStatus::Nonexistent => Ok(FileStatus::Nonexistent),
+            FileStatus::Existent(mut meta) => {
+                meta.hash = match prev {
+                    FileStatus::Existent(prev)
+                        if prev.hash.is_some() && prev.same_metadata(&meta) && !prev.is_racy() =>
+                    {
+                        prev.hash.clone()
+                    }
+                    _ => hash()?,
+                };
+                Ok(FileStatus::Existent(meta))
+            }
+        }
+    }
 }
 
 pub enum FileChange {
@@ -14,7 +103,7 @@ pub enum FileChange {
     Del,
 }
 
-#[derive(Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub enum SyncAction {
     None,
     PossibleConflict,
@@ -24,6 +113,19 @@ pub enum SyncAction {
     DeleteGpg,
 }
 
+impl SyncAction {
+    /// Returns whether performing the action writes or deletes a file.
+    pub fn modifies_files(&self) -> bool {
+        match self {
+            SyncAction::None | SyncAction::PossibleConflict => false,
+            SyncAction::PushPlain
+            | SyncAction::DeletePlain
+            | SyncAction::PushGpg
+            | SyncAction::DeleteGpg => true,
+        }
+    }
+}
+
 pub fn determine_sync_action(plain: FileChange, gpg: FileChange) -> SyncAction {
     // TODO if files were conflicted, deletion or modification of one should not trigger a change to the other
     match (plain, gpg) {
@@ -51,7 +153,7 @@ pub fn determine_sync_action(plain: FileChange, gpg: FileChange) -> SyncAction {
         (FileChange::Del, FileChange::NoChange(FileStatus::Nonexistent)) => SyncAction::None,
         (FileChange::Del, FileChange::NoChange(FileStatus::Existent(_))) => SyncAction::DeleteGpg,
         (FileChange::Del, FileChange::Add) => SyncAction::PushGpg, // add wins over del
-        (FileChange::Del, FileChange::Mod) => SyncAction::PushPlain, // mod wins over del
+        (FileChange::Del, FileChange::Mod) => SyncAction::PushGpg, // mod wins over del
         (FileChange::Del, FileChange::Del) => SyncAction::None,
     }
 }
@@ -63,20 +165,132 @@ pub fn determine_file_change(prev: FileStatus, cur: FileStatus) -> FileChange {
         }
         (FileStatus::Nonexistent, FileStatus::Existent(_)) => FileChange::Add,
         (FileStatus::Existent(_), FileStatus::Nonexistent) => FileChange::Del,
-        (FileStatus::Existent(t1), FileStatus::Existent(t2)) => {
-            if t1 != t2 {
-                FileChange::Mod
+        (FileStatus::Existent(prev), FileStatus::Existent(cur)) => {
+            if prev.unchanged(&cur) {
+                FileChange::NoChange(FileStatus::Existent(cur))
             } else {
-                FileChange::NoChange(FileStatus::Existent(t1))
+                FileChange::Mod
             }
         }
     }
 }
 
+/// Determines how to sync a directory, given whether it existed on both
+/// sides after the last sync and whether it exists on each side now.
+///
+/// A directory added on one side is created on the other side, one deleted on
+/// one side is deleted on the other side.  Directories have no contents of
+/// their own, so they never conflict.
+pub fn determine_dir_sync_action(synced: bool, plain_exists: bool, gpg_exists: bool) -> SyncAction {
+    match (plain_exists, gpg_exists) {
+        (true, true) | (false, false) => SyncAction::None,
+        (true, false) if synced => SyncAction::DeletePlain,
+        (true, false) => SyncAction::PushPlain,
+        (false, true) if synced => SyncAction::DeleteGpg,
+        (false, true) => SyncAction::PushGpg,
+    }
+}
+
 #[cfg(test)]
 mod test {
+    use super::{
+        determine_dir_sync_action, determine_file_change, determine_sync_action, FileChange,
+        FileMeta, FileStatus, SyncAction,
+    };
+    use std::time::{Duration, UNIX_EPOCH};
+
     #[test]
     fn test_sync_logic() {
         // TODO simulate common situations
+
+        // a modification wins over a deletion on the other side
+        assert_eq!(
+            determine_sync_action(FileChange::Mod, FileChange::Del),
+            SyncAction::PushPlain
+        );
+        assert_eq!(
+            determine_sync_action(FileChange::Del, FileChange::Mod),
+            SyncAction::PushGpg
+        );
+    }
+
+    fn existent(size: u64, mtime_nanos: u64, hash: Option<&[u8]>) -> FileStatus {
+        FileStatus::Existent(FileMeta {
+            size,
+            mtime: UNIX_EPOCH + Duration::from_nanos(mtime_nanos),
+            inode: 1,
+            hash: hash.map(|h| h.to_vec()),
+            checked: UNIX_EPOCH + Duration::from_secs(10),
+        })
+    }
+
+    fn is_mod(prev: FileStatus, cur: FileStatus) -> bool {
+        matches!(determine_file_change(prev, cur), FileChange::Mod)
+    }
+
+    #[test]
+    fn test_file_change() {
+        // only a different hash counts, not a touch
+        assert!(!is_mod(
+            existent(4, 1, Some(b"a")),
+            existent(4, 2, Some(b"a"))
+        ));
+        assert!(is_mod(
+            existent(4, 1, Some(b"a")),
+            existent(4, 1, Some(b"b"))
+        ));
+
+        // without the current hash, the metadata decide
+        assert!(!is_mod(existent(4, 1, Some(b"a")), existent(4, 1, None)));
+        assert!(is_mod(existent(4, 1, Some(b"a")), existent(5, 1, None)));
+        assert!(is_mod(existent(4, 1, Some(b"a")), existent(4, 2, None)));
+
+        // a whole-second mtime shortly before the check can't be trusted
+        let racy = 9_000_000_000;
+        assert!(is_mod(existent(4, racy, None), existent(4, racy, None)));
+        let old = 1_000_000_000;
+        assert!(!is_mod(existent(4, old, None), existent(4, old, None)));
+    }
+
+    #[test]
+    fn test_with_hash() {
+        let prev = existent(4, 1, Some(b"a"));
+        let hashed = existent(4, 1, None)
+            .with_hash(&prev, || panic!("unmodified file hashed"))
+            .unwrap();
+        assert_eq!(hashed.hash(), Some(&b"a".to_vec()));
+
+        let hashed = existent(5, 1, None)
+            .with_hash(&prev, || Ok(Some(b"b".to_vec())))
+            .unwrap();
+        assert_eq!(hashed.hash(), Some(&b"b".to_vec()));
+    }
+
+    #[test]
+    fn test_dir_sync_action() {
+        assert_eq!(
+            determine_dir_sync_action(false, true, false),
+            SyncAction::PushPlain
+        );
+        assert_eq!(
+            determine_dir_sync_action(true, true, false),
+            SyncAction::DeletePlain
+        );
+        assert_eq!(
+            determine_dir_sync_action(false, false, true),
+            SyncAction::PushGpg
+        );
+        assert_eq!(
+            determine_dir_sync_action(true, false, true),
+            SyncAction::DeleteGpg
+        );
+        assert_eq!(
+            determine_dir_sync_action(true, true, true),
+            SyncAction::None
+        );
+        assert_eq!(
+            determine_dir_sync_action(true, false, false),
+            SyncAction::None
+        );
     }
 }
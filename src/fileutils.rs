@@ -1,14 +1,36 @@
-use crate::filesync::FileStatus;
+use crate::filesync::{FileMeta, FileStatus};
 use std::fs::{DirEntry, File};
+use std::io;
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
+/// Reads the metadata of the file at `fp`.  The hash is left unknown, see
+/// `FileStatus::with_hash`.
 pub fn file_status(fp: &PathBuf) -> std::io::Result<FileStatus> {
-    if !fp.exists() {
-        return Ok(FileStatus::Nonexistent);
-    }
+    let metadata = match std::fs::metadata(fp) {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(FileStatus::Nonexistent),
+        Err(e) => return Err(e),
+    };
+
+    Ok(FileStatus::Existent(FileMeta {
+        size: metadata.len(),
+        mtime: metadata.modified()?,
+        inode: inode(&metadata),
+        hash: None,
+        checked: SystemTime::now(),
+    }))
+}
 
-    let mtime = std::fs::metadata(&fp)?.modified()?;
-    Ok(FileStatus::Existent(mtime))
+#[cfg(unix)]
+fn inode(metadata: &std::fs::Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+    metadata.ino()
+}
+
+#[cfg(not(unix))]
+fn inode(_metadata: &std::fs::Metadata) -> u64 {
+    0
 }
 
 pub fn visit_dir(dir: &Path, cb: &mut dyn FnMut(&DirEntry)) -> std::io::Result<()> {
@@ -26,13 +48,157 @@ pub fn visit_dir(dir: &Path, cb: &mut dyn FnMut(&DirEntry)) -> std::io::Result<(
     Ok(())
 }
 
+/// Calls `cb` with every directory below `dir`, each before its
+/// subdirectories.
+pub fn visit_subdirs(dir: &Path, cb: &mut dyn FnMut(&Path)) -> std::io::Result<()> {
+    if dir.is_dir() {
+        for entry in std::fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.is_dir() {
+                cb(&path);
+                visit_subdirs(&path, cb)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Removes `dir` and its subdirectories, as far as they are empty.
+pub fn remove_empty_dirs(dir: &Path) -> io::Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            remove_empty_dirs(&entry.path())?;
+        }
+    }
+    if std::fs::read_dir(dir)?.next().is_none() {
+        std::fs::remove_dir(dir)?;
+    }
+    Ok(())
+}
+
 pub fn open_read(filename: &Path) -> std::io::Result<File> {
     File::open(filename)
 }
 
-pub fn open_write(filename: &Path) -> std::io::Result<File> {
-    std::fs::OpenOptions::new()
+/// Path of the temporary file used while `target` is written atomically.  It
+/// is hidden and lives in the same directory, so that it can be renamed over
+/// `target`.  Its name is short, so that any legal name can be written, and
+/// derived from a hash of the name of `target`, so that an interrupted write
+/// can still be cleaned up.
+fn temp_path(target: &Path) -> PathBuf {
+    use sha2::{Digest, Sha256};
+
+    let hash = Sha256::digest(target.file_name().unwrap().as_encoded_bytes());
+    let hex = hash[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    target.with_file_name(format!(".gpgsync-tmp-{}", hex))
+}
+
+/// Removes the temporary file of an atomic write of `target` that was
+/// interrupted, if there is one.
+pub fn remove_stale_temp_file(target: &Path) -> io::Result<()> {
+    match std::fs::remove_file(temp_path(target)) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Replaces the contents of `target` with what `write` writes to a file.
+///
+/// The new contents go to a temporary file first, which is synced to disk and
+/// then renamed over `target`.  Thus readers see either the old or the
+/// complete new contents, and a failed `write` leaves `target` untouched.
+/// Missing parent directories of `target` are created, and the permissions of
+/// an existing `target` are kept.
+pub fn write_atomically(
+    target: &Path,
+    write: impl FnOnce(&mut File) -> io::Result<()>,
+) -> io::Result<()> {
+    write_atomically_if(target, |f| write(f).map(|()| true)).map(|_| ())
+}
+
+/// Like `write_atomically`, but `target` is only replaced if `write` returns
+/// true.  Returns whether it was replaced.
+pub fn write_atomically_if(
+    target: &Path,
+    write: impl FnOnce(&mut File) -> io::Result<bool>,
+) -> io::Result<bool> {
+    let tmp = temp_path(target);
+
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let result = std::fs::OpenOptions::new()
         .write(true)
         .create(true)
-        .open(filename)
+        .truncate(true)
+        .open(&tmp)
+        .and_then(|mut f| {
+            if let Ok(metadata) = std::fs::metadata(target) {
+                f.set_permissions(metadata.permissions())?;
+            }
+            if !write(&mut f)? {
+                return Ok(false);
+            }
+            f.sync_all()?;
+            Ok(true)
+        });
+
+    match result {
+        Ok(true) => std::fs::rename(&tmp, target).map(|()| true),
+        result => {
+            let _ = std::fs::remove_file(&tmp);
+            result
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{write_atomically, write_atomically_if};
+    use std::io::{self, Write};
+
+    #[test]
+    fn test_write_atomically() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let target = dir.join("sub").join("file.txt");
+
+        write_atomically(&target, |f| f.write_all(b"long contents")).unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"long contents");
+
+        // shorter contents don't leave the old tail behind
+        write_atomically(&target, |f| f.write_all(b"short")).unwrap();
+        assert_eq!(std::fs::read(&target).unwrap(), b"short");
+
+        // a failed write leaves the target and no temporary file behind
+        assert!(write_atomically(&target, |f| {
+            f.write_all(b"partial")?;
+            Err(io::Error::other("failed"))
+        })
+        .is_err());
+        assert_eq!(std::fs::read(&target).unwrap(), b"short");
+        assert_eq!(std::fs::read_dir(dir.join("sub")).unwrap().count(), 1);
+
+        // neither does a write that decides against replacing the target
+        assert!(!write_atomically_if(&target, |f| {
+            f.write_all(b"same")?;
+            Ok(false)
+        })
+        .unwrap());
+        assert_eq!(std::fs::read(&target).unwrap(), b"short");
+        assert_eq!(std::fs::read_dir(dir.join("sub")).unwrap().count(), 1);
+
+        // the temporary file's name is short enough for any legal name
+        let long = dir.join("n".repeat(250));
+        write_atomically(&long, |f| f.write_all(b"long name")).unwrap();
+        assert_eq!(std::fs::read(&long).unwrap(), b"long name");
+    }
 }
@@ -1,51 +1,163 @@
-use std::io::Write;
+use std::io::{self, Read, Write};
+use std::sync::Mutex;
 
-// TODO refactor function into signature Read -> Write function
-pub fn decrypt<'c, 'p, C, P>(
+use crate::backend::{is_openpgp_message, CryptoBackend};
+use crate::policy::EncryptionPolicy;
+
+/// Encryption with GnuPG, using the gpgme library.  Produces `.gpg` files.
+///
+/// The backend is a long-lived session: gpgme contexts are set up once and
+/// reused by the following operations instead of being created for every
+/// file.  Contexts that are in use by another thread aren't shared, a new one
+/// is created then.
+///
+/// The key derivation from the passphrase can't be skipped this way, gpg
+/// salts it differently for every file.
+pub struct GpgmeBackend {
+    /// Contexts that are currently not in use.
+    contexts: Mutex<Vec<gpgme::Context>>,
+}
+
+impl GpgmeBackend {
+    pub fn new() -> Self {
+        Self {
+            contexts: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Runs `f` with an idle context, or a new one if there is none.
+    ///
+    /// The context is only put back if `f` succeeds, so that a context that
+    /// is left in a bad state by a failed operation isn't used again.
+    fn with_context<T>(
+        &self,
+        f: impl FnOnce(&mut gpgme::Context) -> gpgme::error::Result<T>,
+    ) -> gpgme::error::Result<T> {
+        let idle = self.contexts.lock().unwrap().pop();
+        let mut ctx = match idle {
+            Some(ctx) => ctx,
+            None => new_context()?,
+        };
+
+        let result = f(&mut ctx)?;
+        self.contexts.lock().unwrap().push(ctx);
+
+        Ok(result)
+    }
+}
+
+impl Default for GpgmeBackend {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CryptoBackend for GpgmeBackend {
+    fn encrypt(
+        &self,
+        plaintext_in: &mut (dyn Read + Send),
+        ciphertext_out: &mut (dyn Write + Send),
+        policy: &EncryptionPolicy,
+    ) -> io::Result<()> {
+        let plaintext_in = gpgme::Data::from_reader(plaintext_in).map_err(|e| e.error())?;
+        let ciphertext_out = gpgme::Data::from_writer(ciphertext_out).map_err(|e| e.error())?;
+        self.with_context(|ctx| encrypt(ctx, plaintext_in, ciphertext_out, policy))?;
+        Ok(())
+    }
+
+    fn decrypt(
+        &self,
+        ciphertext_in: &mut (dyn Read + Send),
+        plaintext_out: &mut (dyn Write + Send),
+        policy: &EncryptionPolicy,
+    ) -> io::Result<()> {
+        let ciphertext_in = gpgme::Data::from_reader(ciphertext_in).map_err(|e| e.error())?;
+        let plaintext_out = gpgme::Data::from_writer(plaintext_out).map_err(|e| e.error())?;
+        self.with_context(|ctx| decrypt(ctx, ciphertext_in, plaintext_out, policy))?;
+        Ok(())
+    }
+
+    fn extension(&self) -> &'static str {
+        "gpg"
+    }
+
+    fn is_ciphertext(&self, header: &[u8]) -> bool {
+        is_openpgp_message(header)
+    }
+}
+
+fn new_context() -> gpgme::error::Result<gpgme::Context> {
+    let mut ctx = gpgme::Context::from_protocol(gpgme::Protocol::OpenPgp)?;
+    ctx.set_flag("no-symkey-cache\0", "1\0")?;
+    Ok(ctx)
+}
+
+fn decrypt<'c, 'p, C, P>(
+    ctx: &mut gpgme::Context,
     ciphertext_in: C,
     plaintext_out: P,
-    passphrase: &[u8],
+    policy: &EncryptionPolicy,
 ) -> gpgme::error::Result<()>
 where
     C: gpgme::IntoData<'c>,
     P: gpgme::IntoData<'p>,
 {
-    let proto = gpgme::Protocol::OpenPgp;
-
-    let mut ctx = gpgme::Context::from_protocol(proto)?;
-    ctx.set_flag("no-symkey-cache\0", "1\0").unwrap();
-    ctx.set_pinentry_mode(gpgme::PinentryMode::Loopback)?;
-    ctx.with_passphrase_provider(
-        |_: gpgme::PassphraseRequest, out: &mut dyn Write| {
-            out.write_all(passphrase)?;
-            Ok(())
-        },
-        |ctx| ctx.decrypt(ciphertext_in, plaintext_out),
-    )?;
+    match policy {
+        EncryptionPolicy::Passphrase(passphrase) => {
+            ctx.set_pinentry_mode(gpgme::PinentryMode::Loopback)?;
+            ctx.with_passphrase_provider(
+                |_: gpgme::PassphraseRequest, out: &mut dyn Write| {
+                    out.write_all(passphrase.expose())?;
+                    Ok(())
+                },
+                |ctx| ctx.decrypt(ciphertext_in, plaintext_out),
+            )?;
+        }
+        EncryptionPolicy::Recipients(_) => {
+            ctx.set_pinentry_mode(gpgme::PinentryMode::Default)?;
+            // the secret key is unlocked by the gpg-agent, which may ask via pinentry
+            ctx.decrypt(ciphertext_in, plaintext_out)?;
+        }
+    }
     Ok(())
 }
 
-// TODO refactor function into signature Read -> Write
-pub fn encrypt<'p, 'c, P, C>(
+fn encrypt<'p, 'c, P, C>(
+    ctx: &mut gpgme::Context,
     plaintext_in: P,
     ciphertext_out: C,
-    passphrase: &[u8],
+    policy: &EncryptionPolicy,
 ) -> gpgme::error::Result<()>
 where
     P: gpgme::IntoData<'p>,
     C: gpgme::IntoData<'c>,
 {
-    let proto = gpgme::Protocol::OpenPgp;
-
-    let mut ctx = gpgme::Context::from_protocol(proto)?;
-    ctx.set_flag("no-symkey-cache\0", "1\0").unwrap();
-    ctx.set_pinentry_mode(gpgme::PinentryMode::Loopback)?;
-    ctx.with_passphrase_provider(
-        |_: gpgme::PassphraseRequest, out: &mut dyn Write| {
-            out.write_all(passphrase)?;
-            Ok(())
-        },
-        |ctx| ctx.encrypt_symmetric(plaintext_in, ciphertext_out),
-    )?;
+    match policy {
+        EncryptionPolicy::Passphrase(passphrase) => {
+            ctx.set_pinentry_mode(gpgme::PinentryMode::Loopback)?;
+            ctx.with_passphrase_provider(
+                |_: gpgme::PassphraseRequest, out: &mut dyn Write| {
+                    out.write_all(passphrase.expose())?;
+                    Ok(())
+                },
+                |ctx| ctx.encrypt_symmetric(plaintext_in, ciphertext_out),
+            )?;
+        }
+        EncryptionPolicy::Recipients(fingerprints) => {
+            ctx.set_pinentry_mode(gpgme::PinentryMode::Default)?;
+            let keys = fingerprints
+                .iter()
+                .map(|fpr| ctx.get_key(fpr.as_str()))
+                .collect::<gpgme::error::Result<Vec<_>>>()?;
+            // the recipients were chosen explicitly by fingerprint, so don't
+            // require them to be certified in the web of trust
+            ctx.encrypt_with_flags(
+                &keys,
+                plaintext_in,
+                ciphertext_out,
+                gpgme::EncryptFlags::ALWAYS_TRUST,
+            )?;
+        }
+    }
     Ok(())
 }
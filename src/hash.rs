@@ -0,0 +1,77 @@
+use std::io::{self, Write};
+
+use sha2::{Digest, Sha256};
+
+/// A writer that hashes everything written to it.
+///
+/// Lets the output of a decryption be hashed while it is produced, without
+/// ever holding the whole plaintext in memory.  The data can be passed on to
+/// an inner writer at the same time.
+pub struct HashWriter<W = io::Sink> {
+    inner: W,
+    hasher: Sha256,
+}
+
+impl HashWriter {
+    pub fn new() -> Self {
+        Self::with_inner(io::sink())
+    }
+}
+
+impl<W: Write> HashWriter<W> {
+    /// Returns a writer that writes to `inner` and hashes what was written.
+    pub fn with_inner(inner: W) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+        }
+    }
+
+    /// Returns the SHA-256 digest of all data written so far.
+    pub fn finalize(self) -> Vec<u8> {
+        self.hasher.finalize().to_vec()
+    }
+}
+
+impl Default for HashWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<W: Write> Write for HashWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HashWriter;
+    use sha2::{Digest, Sha256};
+    use std::io::Write;
+
+    #[test]
+    fn test_hash_writer() {
+        let data = vec![7u8; 100_000];
+
+        let mut w = HashWriter::new();
+        for chunk in data.chunks(999) {
+            w.write_all(chunk).unwrap();
+        }
+
+        assert_eq!(w.finalize(), Sha256::digest(&data).to_vec());
+
+        let mut copy = Vec::new();
+        let mut w = HashWriter::with_inner(&mut copy);
+        w.write_all(&data).unwrap();
+        assert_eq!(w.finalize(), Sha256::digest(&data).to_vec());
+        assert_eq!(copy, data);
+    }
+}
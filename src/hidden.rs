@@ -0,0 +1,148 @@
+use std::path::Path;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use lazy_static::lazy_static;
+
+/// Names of hidden files and directories that are never synced, because they
+/// belong to a version control system, an editor or another sync client and
+/// make no sense, or even harm, on another machine.
+const DENYLIST: &[&str] = &[
+    // version control
+    ".git",
+    ".hg",
+    ".svn",
+    // vim swap files, emacs and LibreOffice lock files
+    ".*.sw[a-p]",
+    ".#*",
+    ".~lock.*#",
+    // metadata of cloud clients
+    ".dropbox",
+    ".dropbox.attr",
+    ".dropbox.cache",
+    ".stfolder",
+    ".stversions",
+    ".syncthing.*.tmp",
+    ".sync_*.db*",
+    ".owncloudsync.log",
+    ".sync",
+    // desktop metadata
+    ".DS_Store",
+    ".Trash",
+    ".Trash-*",
+];
+
+/// Name of the exclude file of GPGsync, the only one of its files that is
+/// synced.
+const SYNCED_OWN_FILENAME: &str = ".gpgsyncignore";
+
+lazy_static! {
+    static ref DENIED: GlobSet = {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in DENYLIST {
+            builder.add(Glob::new(pattern).unwrap());
+        }
+        builder.build().unwrap()
+    };
+}
+
+/// Which hidden files, i. e. those whose name starts with a '.', are synced.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum HiddenFiles {
+    /// Sync hidden files, except for GPGsync's own files and those on the
+    /// denylist.
+    #[default]
+    Sync,
+    /// Skip all hidden files and everything in hidden directories.
+    Skip,
+}
+
+impl HiddenFiles {
+    /// Returns whether the file or directory at `rel`, relative to a root, is
+    /// skipped, either itself or by one of its parent directories.
+    pub fn skips(self, rel: &Path) -> bool {
+        is_denied(rel)
+            || (self == HiddenFiles::Skip
+                && rel
+                    .iter()
+                    .any(|name| is_hidden_name(&name.to_string_lossy())))
+    }
+}
+
+impl FromStr for HiddenFiles {
+    type Err = anyhow::Error;
+
+    /// Parses "sync" or "skip".
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s {
+            "sync" => Ok(HiddenFiles::Sync),
+            "skip" => Ok(HiddenFiles::Skip),
+            _ => Err(anyhow!(
+                "invalid hidden file policy {:?}, expected sync or skip",
+                s
+            )),
+        }
+    }
+}
+
+/// Returns whether `rel`, relative to a root, is one of GPGsync's own files,
+/// e. g. its database or a temporary file, is on the denylist, or is in such
+/// a directory.  These are never synced.
+pub fn is_denied(rel: &Path) -> bool {
+    rel.iter().any(|name| {
+        let name = name.to_string_lossy();
+        is_hidden_name(&name) && (is_own_file(&name) || DENIED.is_match(name.as_ref()))
+    })
+}
+
+fn is_hidden_name(name: &str) -> bool {
+    name.starts_with('.') && name != "." && name != ".."
+}
+
+/// Returns whether the hidden file `name` is one of the files that GPGsync
+/// writes next to the synced files, e. g. `.gpgsyncdb`, `.gpgsync-base` or
+/// a temporary `.gpgsync-tmp-<hash>`.  Earlier versions named the temporary
+/// files like `.notes.txt.gpgsync-tmp`.
+fn is_own_file(name: &str) -> bool {
+    (name.starts_with(".gpgsync") && name != SYNCED_OWN_FILENAME)
+        || name.ends_with(".gpgsync-tmp")
+        || name.ends_with(".gpgsync-merge")
+}
+
+#[cfg(test)]
+mod test {
+    use super::HiddenFiles;
+    use std::path::Path;
+
+    #[test]
+    fn test_skips() {
+        let synced = |rel: &str| !HiddenFiles::Sync.skips(Path::new(rel));
+        assert!(synced("notes.txt"));
+        assert!(synced(".envrc"));
+        assert!(synced(".config/app/settings.toml"));
+        assert!(synced(".org-id-locations"));
+        assert!(synced(".gpgsyncignore"));
+        assert!(synced("sub/.gitignore"));
+
+        assert!(!synced(".gpgsyncdb"));
+        assert!(!synced(".gpgsyncdb.bak"));
+        assert!(!synced(".gpgsync.toml"));
+        assert!(!synced(".gpgsync-base/notes.txt.gpg"));
+        assert!(!synced("sub/.gpgsync-tmp-0123456789abcdef"));
+        assert!(!synced("sub/.notes.txt.gpgsync-tmp"));
+        assert!(!synced(".git/config"));
+        assert!(!synced("sub/.notes.txt.swp"));
+        assert!(!synced(".#notes.txt"));
+        assert!(!synced(".~lock.table.ods#"));
+        assert!(!synced(".dropbox.cache/x"));
+        assert!(!synced(".stfolder"));
+
+        assert!(!HiddenFiles::Skip.skips(Path::new("notes.txt")));
+        assert!(HiddenFiles::Skip.skips(Path::new(".envrc")));
+        assert!(HiddenFiles::Skip.skips(Path::new(".config/app/settings.toml")));
+
+        assert_eq!("skip".parse::<HiddenFiles>().unwrap(), HiddenFiles::Skip);
+        assert!("all".parse::<HiddenFiles>().is_err());
+    }
+}
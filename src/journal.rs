@@ -0,0 +1,168 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::filesync::{FileStatus, SyncAction};
+use crate::fileutils;
+use crate::syncentity::SyncEntity;
+
+/// A sync action that is about to be performed, together with the statuses
+/// of both files before it.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Intent {
+    pub rel_path_without_gpg: PathBuf,
+    pub action: SyncAction,
+    pub plain_status: FileStatus,
+    pub gpg_status: FileStatus,
+}
+
+impl Intent {
+    pub fn new(se: &SyncEntity, action: SyncAction) -> io::Result<Self> {
+        Ok(Self {
+            rel_path_without_gpg: se.rel_without_gpg().clone(),
+            action,
+            plain_status: fileutils::file_status(&se.as_plain())?,
+            gpg_status: fileutils::file_status(&se.as_gpg())?,
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+enum Record {
+    Begin(Intent),
+    Done(PathBuf),
+}
+
+/// Write-ahead journal of the sync actions whose results are not yet in the
+/// saved database.
+///
+/// Each action that modifies a file is recorded before it is performed, and
+/// marked as done once the database containing its result has been saved.
+/// The records are appended as one JSON object per line and synced to disk
+/// right away.
+pub struct Journal {
+    file: File,
+}
+
+impl Journal {
+    /// Opens the journal at `fp`, creating it if necessary.  Also returns the
+    /// intents that were not marked as done, of actions that may have been
+    /// interrupted.
+    pub fn open(fp: &Path) -> io::Result<(Self, Vec<Intent>)> {
+        let mut unfinished: Vec<Intent> = Vec::new();
+
+        match File::open(fp) {
+            Ok(mut f) => {
+                let mut contents = Vec::new();
+                f.read_to_end(&mut contents)?;
+                for line in contents.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+                    match serde_json::from_slice(line) {
+                        Ok(Record::Begin(intent)) => unfinished.push(intent),
+                        Ok(Record::Done(p)) => {
+                            unfinished.retain(|intent| intent.rel_path_without_gpg != p)
+                        }
+                        Err(e) => {
+                            // a crash while appending cuts off the last record
+                            println!("ignoring incomplete journal record: {}", e);
+                            break;
+                        }
+                    }
+                }
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(fp)?;
+
+        Ok((Self { file }, unfinished))
+    }
+
+    /// Records that the actions of `intents` are about to be performed.
+    pub fn begin(&mut self, intents: impl IntoIterator<Item = Intent>) -> io::Result<()> {
+        self.append(intents.into_iter().map(Record::Begin))
+    }
+
+    /// Records that the results of the actions on the entities at `rel_paths`
+    /// have been saved to the database.
+    pub fn done(&mut self, rel_paths: impl IntoIterator<Item = PathBuf>) -> io::Result<()> {
+        self.append(rel_paths.into_iter().map(Record::Done))
+    }
+
+    /// Removes all records, when no actions are in progress.
+    pub fn clear(&mut self) -> io::Result<()> {
+        self.file.set_len(0)?;
+        self.file.sync_data()
+    }
+
+    fn append(&mut self, records: impl Iterator<Item = Record>) -> io::Result<()> {
+        let mut buf = Vec::new();
+        for record in records {
+            serde_json::to_writer(&mut buf, &record)?;
+            buf.push(b'\n');
+        }
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        self.file.write_all(&buf)?;
+        self.file.sync_data()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Intent, Journal};
+    use crate::filesync::{FileMeta, FileStatus, SyncAction};
+    use std::io::Write;
+    use std::path::PathBuf;
+    use std::time::UNIX_EPOCH;
+
+    fn intent(p: &str) -> Intent {
+        Intent {
+            rel_path_without_gpg: PathBuf::from(p),
+            action: SyncAction::PushPlain,
+            plain_status: FileStatus::Existent(FileMeta {
+                size: 3,
+                mtime: UNIX_EPOCH,
+                inode: 1,
+                hash: None,
+                checked: UNIX_EPOCH,
+            }),
+            gpg_status: FileStatus::Nonexistent,
+        }
+    }
+
+    #[test]
+    fn test_unfinished() {
+        let dir = tempfile::tempdir().unwrap();
+        let fp = dir.path().join("journal");
+
+        {
+            let (mut journal, unfinished) = Journal::open(&fp).unwrap();
+            assert!(unfinished.is_empty());
+            journal.begin(vec![intent("a"), intent("b")]).unwrap();
+            journal.done(vec![PathBuf::from("a")]).unwrap();
+        }
+        // cut off while appending
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&fp)
+            .unwrap()
+            .write_all(b"{\"Begin\":{\"rel_pa")
+            .unwrap();
+
+        let (mut journal, unfinished) = Journal::open(&fp).unwrap();
+        assert_eq!(unfinished.len(), 1);
+        assert_eq!(unfinished[0].rel_path_without_gpg, PathBuf::from("b"));
+        assert_eq!(unfinished[0].action, SyncAction::PushPlain);
+
+        journal.clear().unwrap();
+        assert!(Journal::open(&fp).unwrap().1.is_empty());
+    }
+}
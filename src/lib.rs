@@ -1,54 +1,138 @@
 use std::collections::HashSet;
 use std::ffi::OsStr;
 //use std::fs::{DirEntry, File};
-use std::io::{self, Cursor, Read};
+use std::io::{self, Read};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
 use anyhow::anyhow;
 
+#[cfg(feature = "age")]
+pub use age_backend::AgeBackend;
+pub use backend::CryptoBackend;
+use conflict::Conflict;
+use exclude::Excludes;
 use filesync::{FileStatus, SyncAction};
+#[cfg(feature = "gpgme")]
+pub use gpg::GpgmeBackend;
+use hash::HashWriter;
+pub use hidden::HiddenFiles;
+use journal::{Intent, Journal};
+use name_cipher::NameCipher;
+pub use passphrase::PassphraseSource;
+pub use policy::EncryptionPolicy;
+use policy::{PolicySet, POLICY_FILENAME};
+pub use rekey::rekey;
+use rename::Side;
+pub use resolve::resolve;
+#[cfg(feature = "pgp")]
+pub use rpgp_backend::RpgpBackend;
 use syncdb::SyncDb;
-use syncentity::SyncEntity;
-
+use syncentity::{SyncEntity, SyncRoots};
+
+#[cfg(feature = "age")]
+mod age_backend;
+mod backend;
+mod conflict;
+mod dirsync;
+mod exclude;
 mod fileread;
 mod filesync;
 mod fileutils;
+#[cfg(feature = "gpgme")]
 mod gpg;
+mod hash;
+mod hidden;
+mod journal;
+mod merge;
+mod name_cipher;
+mod names;
+mod passphrase;
+mod policy;
+mod rekey;
+mod rename;
+mod resolve;
+#[cfg(feature = "pgp")]
+mod rpgp_backend;
+mod secret;
 mod syncdb;
 mod syncentity;
 
 /// File name of the database.  Will be saved inside the plain root directory.
 const DB_FILENAME: &str = ".gpgsyncdb";
 
+/// File name of the journal of sync actions in progress.  Will be saved inside the plain root directory.
+const JOURNAL_FILENAME: &str = ".gpgsyncjournal";
+
 /// Delay for which filesystem events are held back to e. g. clean up duplicates.
 const WATCHER_DEBOUNCE_DURATION: Duration = Duration::from_secs(1);
 
+/// Number of files after which the database is saved during the initial sync.
+const DB_CHECKPOINT_INTERVAL: usize = 100;
+
+/// Options of a sync that don't affect how the files are encrypted.
+#[derive(Clone, Debug, Default)]
+pub struct SyncOptions {
+    /// Gitignore-style globs of the paths that are not synced, in addition to
+    /// the exclude files.
+    pub exclude: Vec<String>,
+    /// Which hidden files are synced.
+    pub hidden_files: HiddenFiles,
+    /// Whether to encrypt the names in the gpg root, which must be empty then.
+    /// Later syncs detect encrypted names by the name key in the gpg root.
+    pub encrypt_names: bool,
+}
+
 /// The GPGsync instance.
-pub struct GpgSync {
+pub struct GpgSync<B: CryptoBackend> {
     /// The sync database is persisted in the `plain_root` across program runs.
     db: SyncDb,
     /// Full path where the DB is stored.
     db_path: PathBuf,
-    /// Directory containing all unencrypted files.
-    plain_root: PathBuf,
-    /// Directory containing all encrypted files.
-    gpg_root: PathBuf,
-    /// Passphrase used for all encryption.
-    passphrase: String,
+    /// Records the sync actions whose results are not yet saved in the DB.
+    journal: Journal,
+    /// The directories containing all unencrypted and all encrypted files.
+    roots: Arc<SyncRoots>,
+    /// Implementation of the en- and decryption.
+    backend: B,
+    /// Determines how the files are en- and decrypted.
+    policies: PolicySet,
+    /// Determines which files are not synced.
+    excludes: Excludes,
+    /// Determines which hidden files are synced.
+    hidden: HiddenFiles,
     /// Channel to receive all file watcher events on.
     rx: std::sync::mpsc::Receiver<notify::DebouncedEvent>,
     /// The file watcher.  Must be kept alive while the program is running
     _watcher: notify::RecommendedWatcher,
 }
 
-impl GpgSync {
+impl<B: CryptoBackend> GpgSync<B> {
     /// Returns a new GPGsync.
     ///
     /// When constructing a new GPGsync, an existing database is loaded if
-    /// existing. An initial sync is performed and the file watcher is started,
-    /// whose events can be processed by calls to `try_process_events()`.
-    pub fn new(plain_root: &Path, gpg_root: &Path, passphrase: &str) -> anyhow::Result<Self> {
+    /// existing. The `policy` is used for all files, except for those that
+    /// rules of a policy file in the plain root apply to.  An initial sync is
+    /// performed and the file watcher is started, whose events can be
+    /// processed by calls to `try_process_events()`.
+    ///
+    /// Files are excluded from the sync by the `.gitignore` and
+    /// `.gpgsyncignore` files in the plain root, by its `.git/info/exclude`,
+    /// and by the gitignore-style globs of the `options`, which also determine
+    /// which hidden files are synced and whether the names in the gpg root
+    /// are encrypted, see `NameCipher`.
+    ///
+    /// The files of the initial sync are en- and decrypted by `jobs` threads.
+    pub fn new(
+        plain_root: &Path,
+        gpg_root: &Path,
+        backend: B,
+        policy: EncryptionPolicy,
+        options: &SyncOptions,
+        jobs: usize,
+    ) -> anyhow::Result<Self> {
         use notify::Watcher;
 
         let plain_root = std::fs::canonicalize(plain_root)?;
@@ -58,64 +142,130 @@ impl GpgSync {
 
         let db_path = &plain_root.join(DB_FILENAME);
 
-        let mut db = SyncDb::load_db(db_path).unwrap_or(SyncDb::new(&gpg_root));
-        if db.gpg_root() != gpg_root {
-            // TODO just delete the db in this case
-            return Err(anyhow!(
-                "existing database for another gpg_root found, unsupported"
-            ));
+        let mut db = load_db(db_path, &gpg_root, backend.extension())?;
+        let policies = PolicySet::load(&plain_root.join(POLICY_FILENAME), policy)?;
+        let name_cipher = load_name_cipher(
+            &mut db,
+            &gpg_root,
+            &backend,
+            &policies,
+            options.encrypt_names,
+        )?;
+
+        let roots = Arc::new(SyncRoots {
+            plain_root,
+            gpg_root,
+            extension: backend.extension(),
+            name_cipher,
+        });
+
+        let (mut journal, unfinished) = Journal::open(&roots.plain_root.join(JOURNAL_FILENAME))?;
+        if !unfinished.is_empty() {
+            recover_unfinished(&unfinished, &roots, &mut db, &backend, &policies)?;
+            db.save_db(db_path)?;
         }
+        journal.clear()?;
 
-        // TODO read .gitignore
+        let excludes = Excludes::load(&roots.plain_root, &options.exclude)?;
+        let hidden = options.hidden_files;
 
         let mut ses = HashSet::new();
-        fileutils::visit_dir(&plain_root, &mut |de| {
-            if !is_hidden(&de.path()) {
-                let se = SyncEntity::from_plain(&de.path(), &plain_root, &gpg_root);
-                ses.insert(se);
+        fileutils::visit_dir(&roots.plain_root, &mut |de| {
+            let se = SyncEntity::from_plain(&de.path(), &roots);
+            if !hidden.skips(se.rel_without_gpg())
+                && !excludes.is_excluded(se.rel_without_gpg(), false)
+            {
+                if db.conflicted_by_copy(se.rel_without_gpg()).is_none() {
+                    ses.insert(se);
+                }
             } else {
                 println!("filtered file {:?}", &de.path());
             }
         })?;
 
-        fileutils::visit_dir(&gpg_root, &mut |de| {
-            if !is_hidden(&de.path()) {
-                // TODO enhance ignoring of files
-                if de.path().extension() == Some(OsStr::new("gpg")) {
-                    let se = SyncEntity::from_gpg(&de.path(), &plain_root, &gpg_root);
+        fileutils::visit_dir(&roots.gpg_root, &mut |de| {
+            let se = SyncEntity::from_gpg(&de.path(), &roots);
+            if let Some(se) = se.filter(|_| is_ciphertext_file(&backend, &de.path())) {
+                if !hidden.skips(se.rel_without_gpg())
+                    && !excludes.is_excluded(se.rel_without_gpg(), false)
+                {
                     ses.insert(se);
                 } else {
-                    println!("In gpg dir, skipping non-.gpg file: {:?}", de)
+                    println!("filtered file {:?}", &de.path());
                 }
-            } else {
-                println!("filtered file {:?}", &de.path());
+            } else if !hidden.skips(de.path().strip_prefix(&roots.gpg_root).unwrap()) {
+                println!(
+                    "In gpg dir, skipping non-.{} file: {:?}",
+                    backend.extension(),
+                    de
+                )
             }
         })?;
 
         // TODO: also add files from db to ses
+        for rel in db.conflicted() {
+            ses.insert(SyncEntity::from_rel(rel, &roots));
+        }
 
+        let mut work = Vec::new();
         for se in ses {
-            let sync_action = analyze_file_and_update_db(&mut db, &se)?;
+            let sync_action = match db.conflict(&se) {
+                Some(conflict) if roots.plain_root.join(&conflict.copy).exists() => {
+                    println!("{:?} is conflicted, not syncing it", &se);
+                    continue;
+                }
+                Some(_) => resolve_conflict(&mut db, &se)?,
+                None => analyze_file(&db, &se)?,
+            };
             println!("SyncAction {:?} {:?}", &se, sync_action);
+            work.push((se, sync_action));
+        }
 
-            perform_sync_action_and_update_db(sync_action, &se, &mut db, &passphrase)?;
-            db.save_db(&db_path);
+        let (work, renames) = rename::detect_renames(work, &db, &backend, &policies)?;
+        for r in &renames {
+            rename::perform_rename(r, &mut db)?;
         }
 
+        perform_sync_actions_in_parallel(
+            work,
+            &mut db,
+            db_path,
+            &mut journal,
+            &backend,
+            &policies,
+            jobs,
+        )?;
+
+        let mut dirs = db.synced_dirs().cloned().collect::<Vec<_>>();
+        for root in &[&roots.plain_root, &roots.gpg_root] {
+            fileutils::visit_subdirs(root, &mut |p| {
+                if let Some(rel) = dir_rel(&roots, p) {
+                    if !hidden.skips(&rel) && !excludes.is_excluded(&rel, true) {
+                        dirs.push(rel);
+                    }
+                }
+            })?;
+        }
+        dirsync::sync_dirs(dirs, &mut db, &roots)?;
+        db.save_db(db_path)?;
+
         // TODO init watcher even before initial sync!
 
         let (tx, rx) = std::sync::mpsc::channel();
         let mut watcher = notify::watcher(tx, WATCHER_DEBOUNCE_DURATION)?;
 
-        watcher.watch(&plain_root, notify::RecursiveMode::Recursive)?;
-        watcher.watch(&gpg_root, notify::RecursiveMode::Recursive)?;
+        watcher.watch(&roots.plain_root, notify::RecursiveMode::Recursive)?;
+        watcher.watch(&roots.gpg_root, notify::RecursiveMode::Recursive)?;
 
         Ok(Self {
             db,
             db_path: db_path.clone(),
-            plain_root: plain_root.clone(),
-            gpg_root: gpg_root.clone(),
-            passphrase: passphrase.to_string(),
+            journal,
+            roots,
+            backend,
+            policies,
+            excludes,
+            hidden,
             rx,
             _watcher: watcher,
         })
@@ -138,6 +288,14 @@ impl GpgSync {
                     | notify::DebouncedEvent::Write(p)
                     | notify::DebouncedEvent::Remove(p) => {
                         self.do_sync_path(dbg!(&p))?;
+                        if self.excludes.is_exclude_file(&p) {
+                            self.reload_excludes(&p)?;
+                        }
+                        if self.is_new_long_name(&p) {
+                            println!("long name {:?} arrived, syncing the gpg root", p);
+                            let gpg_root = self.roots.gpg_root.clone();
+                            self.sync_dir(&gpg_root)?;
+                        }
                     }
                     notify::DebouncedEvent::Chmod(_) => {
                         println!("chmod");
@@ -146,19 +304,31 @@ impl GpgSync {
                         println!("Rename event, from {:?} to {:?}", p_src, p_dst);
                         // we don't support moving between the two directories
                         // TODO ?why not
-                        if !((p_src.starts_with(&self.plain_root)
-                            && p_dst.starts_with(&self.plain_root))
-                            || (p_src.starts_with(&self.gpg_root)
-                                && p_dst.starts_with(&self.gpg_root)))
+                        if !((p_src.starts_with(&self.roots.plain_root)
+                            && p_dst.starts_with(&self.roots.plain_root))
+                            || (p_src.starts_with(&self.roots.gpg_root)
+                                && p_dst.starts_with(&self.roots.gpg_root)))
                         {
                             return Err(anyhow!(
                                 "moving between the two directories not supported"
                             ));
                         }
 
-                        // don't do anything smart for now. Just trigger two sync actions, on p_src and p_dst
+                        if !self.is_filtered(&p_src) && !self.is_filtered(&p_dst) {
+                            if p_dst.is_dir() {
+                                self.rename_counterpart_dir(&p_src, &p_dst)?;
+                            } else {
+                                self.rename_counterpart(&p_src, &p_dst)?;
+                            }
+                        }
+                        // syncs whatever the rename didn't take care of
                         self.do_sync_path(&p_src)?;
                         self.do_sync_path(&p_dst)?;
+                        for p in &[p_src, p_dst] {
+                            if self.excludes.is_exclude_file(p) {
+                                self.reload_excludes(p)?;
+                            }
+                        }
                     }
                     notify::DebouncedEvent::Rescan => {}
                     notify::DebouncedEvent::Error(e, po) => {
@@ -173,25 +343,232 @@ impl GpgSync {
         Ok(())
     }
 
+    /// Returns the side of the sync that `p` is on, and its sync entity.
+    /// Returns `None` for a file in the gpg root that no plain file is
+    /// encrypted to.
+    fn entity_at(&self, p: &Path) -> Option<(Side, SyncEntity)> {
+        if p.starts_with(&self.roots.plain_root) {
+            Some((Side::Plain, SyncEntity::from_plain(p, &self.roots)))
+        } else {
+            SyncEntity::from_gpg(p, &self.roots).map(|se| (Side::Gpg, se))
+        }
+    }
+
+    /// Renames the counterpart of the file renamed from `src` to `dst` on one
+    /// side, instead of deleting it and encrypting the file anew, if it can
+    /// simply be renamed along.
+    fn rename_counterpart(&mut self, src: &Path, dst: &Path) -> anyhow::Result<()> {
+        let ((side, from), (_, to)) = match (self.entity_at(src), self.entity_at(dst)) {
+            (Some(from), Some(to)) => (from, to),
+            _ => return Ok(()),
+        };
+
+        if let Some(r) = rename::watched_rename(from, to, side, &self.db, &self.policies)? {
+            rename::perform_rename(&r, &mut self.db)?;
+            self.db.save_db(&self.db_path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the side of the sync that the directory `p` is on, and its
+    /// path relative to the plain root, see `dir_rel()`.
+    fn dir_at(&self, p: &Path) -> Option<(Side, PathBuf)> {
+        let side = if p.starts_with(&self.roots.plain_root) {
+            Side::Plain
+        } else {
+            Side::Gpg
+        };
+        dir_rel(&self.roots, p).map(|rel| (side, rel))
+    }
+
+    /// Renames the counterpart of the directory renamed from `src` to `dst` on
+    /// one side, if it can simply be renamed along.
+    fn rename_counterpart_dir(&mut self, src: &Path, dst: &Path) -> anyhow::Result<()> {
+        let ((side, from), (_, to)) = match (self.dir_at(src), self.dir_at(dst)) {
+            (Some(from), Some(to)) => (from, to),
+            _ => return Ok(()),
+        };
+
+        let dir_rename =
+            rename::watched_dir_rename(from, to, side, &self.roots, &self.db, &self.policies)?;
+        if let Some(r) = dir_rename {
+            rename::perform_dir_rename(&r, &self.roots, &mut self.db)?;
+            self.db.save_db(&self.db_path)?;
+        }
+
+        Ok(())
+    }
+
+    /// Syncs the directory `p` with all files and directories in it, e. g.
+    /// after it was created or moved into one of the roots.
+    fn sync_dir(&mut self, p: &Path) -> anyhow::Result<()> {
+        let (side, rel) = match self.dir_at(p) {
+            Some(dir) => dir,
+            None => return Ok(()),
+        };
+        let mut paths = Vec::new();
+        fileutils::visit_dir(p, &mut |de| paths.push(de.path()))?;
+
+        for p in paths {
+            if side == Side::Plain || is_ciphertext_file(&self.backend, &p) {
+                self.do_sync_path(&p)?;
+            }
+        }
+
+        let mut dirs = vec![rel];
+        let (roots, excludes, hidden) = (&self.roots, &self.excludes, self.hidden);
+        fileutils::visit_subdirs(p, &mut |p| {
+            if let Some(rel) = dir_rel(roots, p) {
+                if !hidden.skips(&rel) && !excludes.is_excluded(&rel, true) {
+                    dirs.push(rel);
+                }
+            }
+        })?;
+        dirs.retain(|rel| rel != Path::new(""));
+        dirsync::sync_dirs(dirs, &mut self.db, &self.roots)?;
+        self.db.save_db(&self.db_path)?;
+
+        Ok(())
+    }
+
+    /// Syncs the deletion of the directory `rel` on `side` file by file, so
+    /// that files modified on the other side in the meantime are kept.  Then
+    /// the directory and its subdirectories are removed on the other side, as
+    /// far as nothing is left in them.
+    fn sync_deleted_dir(&mut self, side: Side, rel: &Path) -> anyhow::Result<()> {
+        for rel in self.db.entities_under(rel) {
+            let se = SyncEntity::from_rel(&rel, &self.roots);
+            match side {
+                Side::Plain => self.do_sync_path(&se.as_plain())?,
+                Side::Gpg => self.do_sync_path(&se.as_gpg())?,
+            }
+        }
+
+        let mut dirs = self.db.dirs_under(rel);
+        dirs.push(rel.to_path_buf());
+        dirs.retain(|rel| !self.hidden.skips(rel) && !self.excludes.is_excluded(rel, true));
+        dirsync::sync_dirs(dirs, &mut self.db, &self.roots)?;
+        self.db.save_db(&self.db_path)?;
+        fileutils::remove_empty_dirs(&merge::base_dir(&self.roots, rel))?;
+
+        Ok(())
+    }
+
+    /// Returns whether the file or directory `p` is hidden and skipped, or
+    /// excluded from the sync.  A path that is gone is taken for a directory
+    /// if the database has entities below it.  Paths in the gpg root are
+    /// matched by their plain path, and skipped if they have none.
+    fn is_filtered(&self, p: &Path) -> bool {
+        match self.dir_at(p) {
+            Some((_, rel))
+                if p.is_dir()
+                    || self.db.has_entities_under(&rel)
+                    || self.db.is_dir_synced(&rel) =>
+            {
+                return self.hidden.skips(&rel) || self.excludes.is_excluded(&rel, true);
+            }
+            None if p.is_dir() => return true,
+            _ => {}
+        }
+        match self.entity_at(p) {
+            Some((_, se)) => {
+                self.hidden.skips(se.rel_without_gpg())
+                    || self.excludes.is_excluded(se.rel_without_gpg(), false)
+            }
+            None => true,
+        }
+    }
+
+    /// Returns whether `p` is an encrypted long name.  It may have been stored
+    /// on another machine, so that the files and directories named by it can
+    /// only be synced now.  Long names stored here are rare enough that
+    /// syncing the gpg root again for them doesn't matter.
+    fn is_new_long_name(&self, p: &Path) -> bool {
+        match &self.roots.name_cipher {
+            Some(name_cipher) => p.is_file() && name_cipher.is_long_name(p),
+            None => false,
+        }
+    }
+
+    /// Reads the exclude files again after the exclude file `p` changed, and
+    /// syncs the directory its rules apply to, on both sides, so that files
+    /// that are no longer excluded get synced.  Files that became excluded
+    /// are left alone.
+    fn reload_excludes(&mut self, p: &Path) -> anyhow::Result<()> {
+        println!("exclude file {:?} changed, reloading the excludes", p);
+        self.excludes.reload()?;
+
+        let rel = p.strip_prefix(&self.roots.plain_root).unwrap();
+        let rel = match rel.parent() {
+            Some(dir) if !rel.starts_with(".git") => dir,
+            _ => Path::new(""),
+        };
+        let dirs = [
+            self.roots.plain_root.join(rel),
+            self.roots.gpg_path(rel, false),
+        ];
+        for dir in &dirs {
+            if dir.is_dir() {
+                self.sync_dir(dir)?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Analyze a file at a path and perform a sync action if necessary.
+    ///
+    /// A directory is synced file by file.  A path that is gone, but has
+    /// entities below it in the database, was a directory that was deleted.
+    /// Hidden and excluded paths are skipped.
     fn do_sync_path(&mut self, p: &Path) -> anyhow::Result<()> {
-        if !is_hidden(&p) {
-            // TODO enhance ignoring of files
-            let se = if p.starts_with(dbg!(&self.plain_root)) {
-                SyncEntity::from_plain(&p.to_path_buf(), &self.plain_root, &self.gpg_root)
-            } else {
-                SyncEntity::from_gpg(&p.to_path_buf(), &self.plain_root, &self.gpg_root)
-            };
-            let sync_action = analyze_file_and_update_db(&mut self.db, &se)?;
+        if !self.is_filtered(p) {
+            if p.is_dir() {
+                return self.sync_dir(p);
+            }
+            if let (false, Some((side, rel))) = (p.exists(), self.dir_at(p)) {
+                if self.db.has_entities_under(&rel) || self.db.is_dir_synced(&rel) {
+                    return self.sync_deleted_dir(side, &rel);
+                }
+            }
+
+            // not filtered, so it is an entity
+            let (_, se) = self.entity_at(p).unwrap();
+
+            let (se, sync_action) =
+                if let Some(rel) = self.db.conflicted_by_copy(se.rel_without_gpg()) {
+                    if p.exists() {
+                        println!("conflict copy {:?} changed", &p);
+                        return Ok(());
+                    }
+                    // deleting the conflict copy resolves the conflict
+                    let se = SyncEntity::from_rel(rel, &self.roots);
+                    let sync_action = resolve_conflict(&mut self.db, &se)?;
+                    (se, sync_action)
+                } else if self.db.conflict(&se).is_some() {
+                    println!("{:?} is conflicted, not syncing it", &se);
+                    return Ok(());
+                } else {
+                    let sync_action = analyze_file(&self.db, &se)?;
+                    (se, sync_action)
+                };
             println!("{:?} {:?}", &p, sync_action);
 
+            if sync_action.modifies_files() {
+                self.journal.begin(vec![Intent::new(&se, sync_action)?])?;
+            }
             perform_sync_action_and_update_db(
                 sync_action,
                 &se,
                 &mut self.db,
-                &self.passphrase, // could be chosen per file as well
+                &self.backend,
+                se.policy(&self.policies),
             )?;
-            self.db.save_db(&self.db_path);
+            self.db.save_db(&self.db_path)?;
+            if sync_action.modifies_files() {
+                self.journal.clear()?;
+            }
         } else {
             println!("filtered file {:?}", &p);
         }
@@ -201,7 +578,7 @@ impl GpgSync {
 }
 
 fn validate_args(plain_root: &PathBuf, gpg_root: &PathBuf) -> anyhow::Result<()> {
-    if plain_root.starts_with(&gpg_root) || gpg_root.starts_with(&plain_root) {
+    if plain_root.starts_with(gpg_root) || gpg_root.starts_with(plain_root) {
         return Err(anyhow!("The two paths must not contain each other."));
     }
 
@@ -215,54 +592,224 @@ fn validate_args(plain_root: &PathBuf, gpg_root: &PathBuf) -> anyhow::Result<()>
     Ok(())
 }
 
-fn file_statuses(se: &SyncEntity) -> io::Result<(FileStatus, FileStatus)> {
-    Ok((
-        fileutils::file_status(&se.as_plain())?,
-        fileutils::file_status(&se.as_gpg())?,
-    ))
+/// Loads the name cipher of `gpg_root`, see `NameCipher::load()`, and checks
+/// that it matches how the names were synced so far according to `db`.  The
+/// name key is encrypted for everybody the `policies` encrypt files for, see
+/// `PolicySet::name_key_policy()`.
+///
+/// Otherwise e. g. a lost name key would make all files in the gpg root look
+/// deleted, and they would be deleted in the plain root.
+fn load_name_cipher(
+    db: &mut SyncDb,
+    gpg_root: &Path,
+    backend: &dyn CryptoBackend,
+    policies: &PolicySet,
+    create: bool,
+) -> anyhow::Result<Option<NameCipher>> {
+    let key_path = name_cipher::key_path(gpg_root, backend.extension());
+    if create && !db.is_empty() && !db.encrypted_names() {
+        return Err(anyhow!(
+            "the names in {:?} are not encrypted, sync to a new, empty directory to encrypt them",
+            gpg_root
+        ));
+    }
+    let name_cipher = if create || key_path.exists() {
+        NameCipher::load(gpg_root, backend, &policies.name_key_policy()?, create)?
+    } else {
+        None
+    };
+    if !db.is_empty() && db.encrypted_names() != name_cipher.is_some() {
+        return Err(match db.encrypted_names() {
+            true => anyhow!(
+                "the names are encrypted, but the name key {:?} is missing",
+                key_path
+            ),
+            false => anyhow!(
+                "found the name key {:?}, but the names were not encrypted so far",
+                key_path
+            ),
+        });
+    }
+    db.set_encrypted_names(name_cipher.is_some());
+
+    Ok(name_cipher)
+}
+
+/// Loads an existing database or creates a new one, and makes sure that it
+/// belongs to `gpg_root`.
+///
+/// If the database is missing or corrupt, e. g. after a crash, its backup is
+/// loaded instead.  Without a usable backup, a new database is created.  The
+/// initial sync then compares the contents of all files present on both sides
+/// and copies over files that only exist on one side.
+///
+/// Other errors, like a database of an unsupported version, are returned.  The
+/// database is left alone then, so that it can still be used by the gpgsync
+/// that wrote it.
+fn load_db(db_path: &PathBuf, gpg_root: &Path, extension: &str) -> anyhow::Result<SyncDb> {
+    let mut db = match SyncDb::load_db(db_path, extension) {
+        Ok(Some(db)) => db,
+        Err(e) if e.kind() != io::ErrorKind::InvalidData => {
+            return Err(anyhow!("could not load the database {:?}: {}", db_path, e));
+        }
+        result => {
+            if let Err(e) = result {
+                println!("could not load the database {:?}: {}", db_path, e);
+                // moved aside, so that it doesn't become the backup on the
+                // next save
+                let mut corrupt_path = db_path.clone().into_os_string();
+                corrupt_path.push(".corrupt");
+                std::fs::rename(db_path, &corrupt_path)?;
+                println!("moved it to {:?}", corrupt_path);
+            }
+            let backup_path = syncdb::backup_path(db_path);
+            match SyncDb::load_db(&backup_path, extension) {
+                Ok(Some(db)) => {
+                    println!("recovered the database from {:?}", backup_path);
+                    db
+                }
+                Ok(None) => SyncDb::new(gpg_root),
+                Err(e) if e.kind() != io::ErrorKind::InvalidData => {
+                    return Err(anyhow!(
+                        "could not load the backup {:?}: {}",
+                        backup_path,
+                        e
+                    ));
+                }
+                Err(e) => {
+                    println!(
+                        "could not load the backup {:?} either: {}, rebuilding the database",
+                        backup_path, e
+                    );
+                    SyncDb::new(gpg_root)
+                }
+            }
+        }
+    };
+    if db.gpg_root() != gpg_root {
+        // TODO just delete the db in this case
+        return Err(anyhow!(
+            "existing database for another gpg_root found, unsupported"
+        ));
+    }
+    db.migrate_names(db_path, extension)?;
+
+    Ok(db)
 }
 
-pub fn check_coincide(se: &SyncEntity, passphrase: &str) -> bool {
-    let gpg_hash = gpg_file_hash(&se.as_gpg(), passphrase).unwrap();
+/// Returns the current statuses of both files of `se`.
+///
+/// The plain file is hashed unless its metadata show that it is unmodified
+/// since `prev`.  The gpg file is never decrypted for this, its hash is only
+/// known if it is unmodified or `in_sync`, i. e. it was just synced with the
+/// plain file.
+fn file_statuses(
+    se: &SyncEntity,
+    prev: &(FileStatus, FileStatus),
+    in_sync: bool,
+) -> io::Result<(FileStatus, FileStatus)> {
+    let plain_status = fileutils::file_status(&se.as_plain())?
+        .with_hash(&prev.0, || plain_file_hash(&se.as_plain()).map(Some))?;
+
+    let gpg_status = fileutils::file_status(&se.as_gpg())?;
+    let gpg_status = if in_sync {
+        let hash = plain_status.hash().cloned();
+        gpg_status.with_hash(&FileStatus::Nonexistent, || Ok(hash))?
+    } else {
+        gpg_status.with_hash(&prev.1, || Ok(None))?
+    };
+
+    Ok((plain_status, gpg_status))
+}
+
+/// Returns the path relative to the plain root of the directory `p` in either
+/// root.  Returns `None` for a directory in the gpg root that no plain
+/// directory maps to.
+fn dir_rel(roots: &SyncRoots, p: &Path) -> Option<PathBuf> {
+    match p.strip_prefix(&roots.plain_root) {
+        Ok(rel) => Some(rel.to_path_buf()),
+        Err(_) => roots.plain_rel(p, false),
+    }
+}
+
+/// Returns whether both files have the same contents after `sync_action` was
+/// performed and returned `conflict`.
+fn is_in_sync(sync_action: SyncAction, conflict: &Option<Conflict>) -> bool {
+    conflict.is_none()
+        && match sync_action {
+            SyncAction::PossibleConflict | SyncAction::PushPlain | SyncAction::PushGpg => true,
+            SyncAction::None | SyncAction::DeletePlain | SyncAction::DeleteGpg => false,
+        }
+}
+
+/// Returns whether `p` has the extension of the backend's ciphertexts and looks like one.
+fn is_ciphertext_file(backend: &dyn CryptoBackend, p: &Path) -> bool {
+    if p.extension() != Some(OsStr::new(backend.extension())) {
+        return false;
+    }
+
+    let mut header = Vec::new();
+    match fileutils::open_read(p) {
+        Ok(f) => f.take(64).read_to_end(&mut header).is_ok() && backend.is_ciphertext(&header),
+        Err(_) => false,
+    }
+}
+
+pub fn check_coincide(
+    se: &SyncEntity,
+    backend: &dyn CryptoBackend,
+    policy: &EncryptionPolicy,
+) -> bool {
+    let gpg_hash = gpg_file_hash(&se.as_gpg(), backend, policy).unwrap();
     let plain_hash = plain_file_hash(&se.as_plain()).unwrap();
     gpg_hash == plain_hash
 }
 
-pub fn push_plain(se: &SyncEntity, passphrase: &str) -> io::Result<()> {
+pub fn push_plain(
+    se: &SyncEntity,
+    backend: &dyn CryptoBackend,
+    policy: &EncryptionPolicy,
+) -> io::Result<()> {
     let mut plain_f = fileutils::open_read(&se.as_plain())?;
 
-    let mut gpg_f = fileutils::open_write(&se.as_gpg())?;
-
-    crate::gpg::encrypt(&mut plain_f, &mut gpg_f, passphrase.as_bytes())?;
+    fileutils::write_atomically(&se.as_gpg_to_create()?, |gpg_f| {
+        backend.encrypt(&mut plain_f, gpg_f, policy)
+    })?;
 
     Ok(())
 }
 
-pub fn push_gpg(se: &SyncEntity, passphrase: &str) -> io::Result<()> {
+/// Decrypts the gpg file to the plain file.  A plain file with the same
+/// contents is left untouched, e. g. if only the mtime of the gpg file changed.
+pub fn push_gpg(
+    se: &SyncEntity,
+    backend: &dyn CryptoBackend,
+    policy: &EncryptionPolicy,
+) -> io::Result<()> {
+    let plain_hash = if se.as_plain().exists() {
+        Some(plain_file_hash(&se.as_plain())?)
+    } else {
+        None
+    };
     let mut gpg_f = fileutils::open_read(&se.as_gpg())?;
 
-    let mut plain_f = fileutils::open_write(&se.as_plain())?;
-
-    crate::gpg::decrypt(&mut gpg_f, &mut plain_f, passphrase.as_bytes()).unwrap();
+    let replaced = fileutils::write_atomically_if(&se.as_plain(), |plain_f| {
+        let mut hasher = HashWriter::with_inner(plain_f);
+        backend.decrypt(&mut gpg_f, &mut hasher, policy)?;
+        Ok(Some(hasher.finalize()) != plain_hash)
+    })?;
+    if !replaced {
+        println!("{:?} has the same contents on both sides", se);
+    }
 
     Ok(())
 }
+/// Returns the SHA-256 hash of everything read from `p`.
 pub fn hash_all(p: &mut impl Read) -> io::Result<Vec<u8>> {
-    use md5::Digest;
-
-    let mut hasher = md5::Md5::new();
-
-    let mut buf: [u8; 1024] = [0; 1024];
-    loop {
-        let n = p.read(&mut buf)?;
-        if n == 0 {
-            break;
-        }
-
-        hasher.update(&buf[0..n]);
-    }
+    let mut hasher = HashWriter::new();
+    io::copy(p, &mut hasher)?;
 
-    Ok(hasher.finalize().to_vec())
+    Ok(hasher.finalize())
 }
 
 pub fn plain_file_hash(p: &Path) -> io::Result<Vec<u8>> {
@@ -271,29 +818,34 @@ pub fn plain_file_hash(p: &Path) -> io::Result<Vec<u8>> {
     hash_all(&mut f)
 }
 
-fn gpg_file_hash(p: &Path, passphrase: &str) -> io::Result<Vec<u8>> {
+fn gpg_file_hash(
+    p: &Path,
+    backend: &dyn CryptoBackend,
+    policy: &EncryptionPolicy,
+) -> io::Result<Vec<u8>> {
     let mut f = fileutils::open_read(p)?;
 
-    let mut decrypted = Vec::new();
+    // hash the plaintext while it is decrypted, so that memory use doesn't
+    // depend on the file size
+    let mut hasher = HashWriter::new();
 
-    gpg::decrypt(&mut f, &mut decrypted, passphrase.as_bytes())?;
+    backend.decrypt(&mut f, &mut hasher, policy)?;
 
-    hash_all(&mut Cursor::new(decrypted))
+    Ok(hasher.finalize())
 }
 
-fn analyze_file_and_update_db(db: &mut SyncDb, se: &SyncEntity) -> io::Result<SyncAction> {
-    let (plain_status_prev, gpg_status_prev) = db.get_file_status(&se);
-
-    let plain_status_cur = dbg!(fileutils::file_status(&se.as_plain()))?;
-    let gpg_status_cur = dbg!(fileutils::file_status(&se.as_gpg()))?;
+/// Determines what needs to be done to sync `se`.  The database is only
+/// updated once the sync action has been performed.
+fn analyze_file(db: &SyncDb, se: &SyncEntity) -> io::Result<SyncAction> {
+    let prev = db.get_file_status(se);
+    let (plain_status_cur, gpg_status_cur) = file_statuses(se, &prev, false)?;
+    let (plain_status_prev, gpg_status_prev) = prev;
 
     let sync_action = filesync::determine_sync_action(
         filesync::determine_file_change(plain_status_prev, plain_status_cur),
         filesync::determine_file_change(gpg_status_prev, gpg_status_cur),
     );
 
-    db.set_file_status(&se, plain_status_cur, gpg_status_cur);
-
     Ok(sync_action)
 }
 
@@ -301,51 +853,258 @@ fn perform_sync_action_and_update_db(
     sync_action: SyncAction,
     se: &SyncEntity,
     db: &mut SyncDb,
-    passphrase: &str,
+    backend: &dyn CryptoBackend,
+    policy: &EncryptionPolicy,
+) -> io::Result<()> {
+    let conflict = perform_sync_action(sync_action, se, backend, policy)?;
+
+    let prev = db.get_file_status(se);
+    let (plain_status, gpg_status) = file_statuses(se, &prev, is_in_sync(sync_action, &conflict))?;
+    db.set_file_status(se, plain_status, gpg_status);
+    if let Some(conflict) = conflict {
+        db.set_conflict(se, conflict);
+    }
+
+    Ok(())
+}
+
+/// Performs the sync actions of the initial sync on `jobs` worker threads.
+///
+/// Only the en- and decryption happens on the workers.  The database is
+/// updated on the calling thread as the results come in, and saved every
+/// `DB_CHECKPOINT_INTERVAL` files and at the end.  After the first error, no
+/// further sync actions are started and the error is returned.
+///
+/// All actions are recorded in the `journal` up front, and marked as done
+/// whenever the database is saved.
+fn perform_sync_actions_in_parallel(
+    work: Vec<(SyncEntity, SyncAction)>,
+    db: &mut SyncDb,
+    db_path: &Path,
+    journal: &mut Journal,
+    backend: &dyn CryptoBackend,
+    policies: &PolicySet,
+    jobs: usize,
 ) -> io::Result<()> {
+    journal.begin(
+        work.iter()
+            .filter(|(_, sync_action)| sync_action.modifies_files())
+            .map(|(se, sync_action)| Intent::new(se, *sync_action))
+            .collect::<io::Result<Vec<_>>>()?,
+    )?;
+
+    let work = work
+        .into_iter()
+        .map(|(se, sync_action)| {
+            let prev = db.get_file_status(&se);
+            (se, sync_action, prev)
+        })
+        .collect::<Vec<_>>();
+
+    let queue = Mutex::new(work.into_iter());
+    let failed = AtomicBool::new(false);
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    std::thread::scope(|s| {
+        for _ in 0..jobs.max(1) {
+            let tx = tx.clone();
+            let (queue, failed) = (&queue, &failed);
+            s.spawn(move || {
+                while !failed.load(Ordering::Relaxed) {
+                    let next = queue.lock().unwrap().next();
+                    let (se, sync_action, prev) = match next {
+                        Some(w) => w,
+                        None => break,
+                    };
+                    let result =
+                        perform_sync_action(sync_action, &se, backend, se.policy(policies))
+                            .and_then(|conflict| {
+                                let in_sync = is_in_sync(sync_action, &conflict);
+                                Ok((conflict, file_statuses(&se, &prev, in_sync)?))
+                            });
+                    if tx.send((se, result)).is_err() {
+                        break;
+                    }
+                }
+            });
+        }
+        // the loop below ends when all workers are done
+        drop(tx);
+
+        let mut first_error = None;
+        let mut completed = Vec::new();
+        for (n, (se, result)) in rx.iter().enumerate() {
+            match result {
+                Ok((conflict, (plain_status, gpg_status))) => {
+                    db.set_file_status(&se, plain_status, gpg_status);
+                    if let Some(conflict) = conflict {
+                        db.set_conflict(&se, conflict);
+                    }
+                    completed.push(se.rel_without_gpg().clone());
+                }
+                Err(e) => {
+                    println!("sync of {:?} failed: {}", &se, e);
+                    failed.store(true, Ordering::Relaxed);
+                    first_error.get_or_insert(e);
+                }
+            }
+            if (n + 1) % DB_CHECKPOINT_INTERVAL == 0 {
+                if let Err(e) = db
+                    .save_db(db_path)
+                    .and_then(|()| journal.done(completed.drain(..)))
+                {
+                    failed.store(true, Ordering::Relaxed);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        db.save_db(db_path)?;
+
+        match first_error {
+            Some(e) => {
+                journal.done(completed)?;
+                Err(e)
+            }
+            None => journal.clear(),
+        }
+    })
+}
+
+/// Settles the sync actions of an earlier run that were begun, but whose
+/// results didn't make it into the database.
+///
+/// If neither file has changed since, the action may not have happened at all
+/// and is performed again.  If only the target has changed and matches its
+/// source, the action was completed and just the database is updated.  In all
+/// other cases, the files were modified otherwise in the meantime, and the
+/// entity is left to the regular sync.
+fn recover_unfinished(
+    intents: &[Intent],
+    roots: &Arc<SyncRoots>,
+    db: &mut SyncDb,
+    backend: &dyn CryptoBackend,
+    policies: &PolicySet,
+) -> io::Result<()> {
+    for intent in intents {
+        let se = SyncEntity::from_rel(&intent.rel_path_without_gpg, roots);
+        let policy = se.policy(policies);
+
+        fileutils::remove_stale_temp_file(&se.as_plain())?;
+        fileutils::remove_stale_temp_file(&se.as_gpg())?;
+
+        let plain_status = fileutils::file_status(&se.as_plain())?;
+        let gpg_status = fileutils::file_status(&se.as_gpg())?;
+        let plain_unchanged = plain_status.same_metadata(&intent.plain_status);
+        let gpg_unchanged = gpg_status.same_metadata(&intent.gpg_status);
+
+        let completed = match intent.action {
+            SyncAction::PushPlain => {
+                plain_unchanged
+                    && gpg_status != FileStatus::Nonexistent
+                    && check_coincide(&se, backend, policy)
+            }
+            SyncAction::PushGpg => {
+                gpg_unchanged
+                    && plain_status != FileStatus::Nonexistent
+                    && check_coincide(&se, backend, policy)
+            }
+            SyncAction::DeletePlain => gpg_unchanged && plain_status == FileStatus::Nonexistent,
+            SyncAction::DeleteGpg => plain_unchanged && gpg_status == FileStatus::Nonexistent,
+            SyncAction::None | SyncAction::PossibleConflict => false,
+        };
+
+        if plain_unchanged && gpg_unchanged {
+            println!("replaying interrupted {:?} of {:?}", intent.action, &se);
+            perform_sync_action_and_update_db(intent.action, &se, db, backend, policy)?;
+        } else if completed {
+            println!("interrupted {:?} of {:?} had completed", intent.action, &se);
+            let (plain_status, gpg_status) = file_statuses(&se, &db.get_file_status(&se), true)?;
+            db.set_file_status(&se, plain_status, gpg_status);
+        } else {
+            println!(
+                "{:?} was modified since {:?} was interrupted, leaving it to the sync",
+                &se, intent.action
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Clears the conflict of `se`, whose conflict copy the user has deleted, and
+/// returns how to sync the version that the user has kept.
+///
+/// That is the plain file, unless the gpg file has been changed again in the
+/// meantime.
+fn resolve_conflict(db: &mut SyncDb, se: &SyncEntity) -> io::Result<SyncAction> {
+    let conflict = db.clear_conflict(se).unwrap();
+    println!("conflict {:?} resolved", &se);
+
+    let plain_exists = se.as_plain().exists();
+    let gpg_status = fileutils::file_status(&se.as_gpg())?;
+    let gpg_exists = gpg_status != FileStatus::Nonexistent;
+
+    Ok(if gpg_status.same_metadata(&conflict.gpg_status) {
+        if plain_exists {
+            SyncAction::PushPlain
+        } else {
+            SyncAction::DeleteGpg
+        }
+    } else {
+        match (plain_exists, gpg_exists) {
+            (true, true) => SyncAction::PossibleConflict,
+            (true, false) => SyncAction::PushPlain,
+            (false, true) => SyncAction::PushGpg,
+            (false, false) => SyncAction::None,
+        }
+    })
+}
+
+/// Performs `sync_action` on the files of `se`.  Returns the conflict if the
+/// two versions turned out to differ.
+fn perform_sync_action(
+    sync_action: SyncAction,
+    se: &SyncEntity,
+    backend: &dyn CryptoBackend,
+    policy: &EncryptionPolicy,
+) -> io::Result<Option<Conflict>> {
     match sync_action {
         SyncAction::None => {}
         SyncAction::PossibleConflict => {
-            if !check_coincide(se, passphrase) {
-                println!("conflict {:?}", &se);
-            // todo mark it as conflicted in db
+            if !check_coincide(se, backend, policy) {
+                if !merge::try_merge(se, backend, policy)? {
+                    return conflict::create_copy(se, backend, policy).map(Some);
+                }
             } else {
                 println!("No Conflict!");
+                merge::store_base(se)?;
             }
         }
         SyncAction::PushPlain => {
-            push_plain(se, passphrase)?;
+            push_plain(se, backend, policy)?;
+            merge::store_base(se)?;
         }
         SyncAction::DeletePlain => {
             std::fs::remove_file(se.as_plain())?;
+            merge::remove_base(se)?;
         }
         SyncAction::PushGpg => {
-            push_gpg(se, passphrase)?;
+            push_gpg(se, backend, policy)?;
+            merge::store_base(se)?;
         }
         SyncAction::DeleteGpg => {
-            std::fs::remove_file(&se.as_gpg())?;
+            std::fs::remove_file(se.as_gpg())?;
+            merge::remove_base(se)?;
         }
     }
-    let (plain_status, gpg_status) = file_statuses(se)?;
-    db.set_file_status(&se, plain_status, gpg_status);
-
-    Ok(())
-}
 
-fn is_hidden(p: &std::path::Path) -> bool {
-    for os_s in p {
-        let s = os_s.to_string_lossy();
-        if s.starts_with(".") && s != "." && s != ".." {
-            return true;
-        }
-    }
-    false
+    Ok(None)
 }
 
-#[cfg(test)]
+#[cfg(all(test, any(feature = "gpgme", feature = "pgp")))]
 mod test {
 
-    use super::GpgSync;
+    use super::{load_db, EncryptionPolicy, GpgSync, SyncDb, SyncOptions, DB_FILENAME};
 
     use lazy_static::lazy_static;
     use std::io::Write;
@@ -355,12 +1114,8 @@ mod test {
     fn poll_predicate(p: &mut dyn FnMut() -> bool, timeout: Duration) {
         let mut remaining = Some(timeout);
         let decrement = Duration::new(0, 3_000_000);
-        loop {
-            if let Some(rem) = remaining {
-                remaining = rem.checked_sub(decrement);
-            } else {
-                break;
-            }
+        while let Some(rem) = remaining {
+            remaining = rem.checked_sub(decrement);
 
             if p() {
                 return;
@@ -376,15 +1131,31 @@ mod test {
         static ref GPG_ROOT: &'static Path = &Path::new("./gpg_root");
     }
 
+    /// The backend for the tests, the pure-Rust one if available so that the
+    /// tests don't need a GnuPG installation.
+    #[cfg(feature = "pgp")]
+    fn backend() -> super::RpgpBackend {
+        super::RpgpBackend
+    }
+
+    #[cfg(not(feature = "pgp"))]
+    fn backend() -> super::GpgmeBackend {
+        super::GpgmeBackend::new()
+    }
+
+    fn passphrase(p: &str) -> EncryptionPolicy {
+        EncryptionPolicy::Passphrase(p.to_string().into())
+    }
+
     fn test_roots(test_name: &str) -> (PathBuf, PathBuf) {
         (PLAIN_ROOT.join(test_name), GPG_ROOT.join(test_name))
     }
 
     fn init_dir(p: &Path) {
         if p.exists() {
-            std::fs::remove_dir_all(&p)?;
+            std::fs::remove_dir_all(p).unwrap();
         }
-        std::fs::create_dir_all(&p)?;
+        std::fs::create_dir_all(p).unwrap();
     }
 
     fn init_dirs(pr: &Path, gr: &Path) {
@@ -396,8 +1167,9 @@ mod test {
         let mut f = std::fs::OpenOptions::new()
             .create_new(true)
             .write(true)
-            .open(p)?;
-        f.write_all(s)?;
+            .open(p)
+            .unwrap();
+        f.write_all(s).unwrap();
     }
 
     #[test]
@@ -415,7 +1187,15 @@ mod test {
         {
             init_dirs(&pr, &gr);
             make_file(&pr.join("notes.txt"), b"hello");
-            let _gpgs = GpgSync::new(&pr, &gr, "test")?;
+            let _gpgs = GpgSync::new(
+                &pr,
+                &gr,
+                backend(),
+                passphrase("test"),
+                &SyncOptions::default(),
+                2,
+            )
+            .unwrap();
             assert!(gr.join("notes.txt.gpg").exists());
         }
 
@@ -423,7 +1203,15 @@ mod test {
         {
             init_dirs(&pr, &gr);
             make_file(&gr.join("notes.txt.gpg"), include_bytes!("notes.txt.gpg"));
-            let _gpgs = GpgSync::new(&pr, &gr, "test")?;
+            let _gpgs = GpgSync::new(
+                &pr,
+                &gr,
+                backend(),
+                passphrase("test"),
+                &SyncOptions::default(),
+                2,
+            )
+            .unwrap();
             assert!(pr.join("notes.txt").exists());
         }
     }
@@ -434,13 +1222,15 @@ mod test {
         let (pr, gr) = test_roots("test_wrong_passphrase");
         init_dirs(&pr, &gr);
         make_file(&gr.join("notes.txt.gpg"), include_bytes!("notes.txt.gpg"));
-        let _gpgs = GpgSync::new(&pr, &gr, "test_wrong_passphrase")?;
-    }
-
-    #[test]
-    fn test_directory_deletion() {
-        // TODO PLAIN_ROOT gets deleted
-        // TODO GPG_ROOT gets deleted
+        let _gpgs = GpgSync::new(
+            &pr,
+            &gr,
+            backend(),
+            passphrase("test_wrong_passphrase"),
+            &SyncOptions::default(),
+            2,
+        )
+        .unwrap();
     }
 
     #[test]
@@ -464,19 +1254,33 @@ mod test {
 
         init_dirs(&pr, &gr);
         make_file(&pr.join("notes.txt"), b"hello");
-        let mut gpgs = GpgSync::new(&pr, &gr, "test")?;
-        assert!(gr.join("notes.txt.gpg").exists());
-
-        std::fs::rename(pr.join("notes.txt"), pr.join("notes_renamed.txt"))?;
+        let mut gpgs = GpgSync::new(
+            &pr,
+            &gr,
+            backend(),
+            passphrase("test"),
+            &SyncOptions::default(),
+            2,
+        )
+        .unwrap();
+        let ciphertext = std::fs::read(gr.join("notes.txt.gpg")).unwrap();
+
+        std::fs::rename(pr.join("notes.txt"), pr.join("notes_renamed.txt")).unwrap();
 
         poll_predicate(
             &mut || {
-                gpgs.try_process_events(Duration::new(0, 200_000_000))?;
+                gpgs.try_process_events(Duration::new(0, 200_000_000))
+                    .unwrap();
 
                 !gr.join("notes.txt.gpg").exists() && gr.join("notes_renamed.txt.gpg").exists()
             },
             Duration::new(2, 0),
         );
+        // renamed along, not encrypted anew with another session key
+        assert_eq!(
+            std::fs::read(gr.join("notes_renamed.txt.gpg")).unwrap(),
+            ciphertext
+        );
     }
 
     #[test]
@@ -484,14 +1288,23 @@ mod test {
         let (pr, gr) = test_roots("test_running_sync");
 
         init_dirs(&pr, &gr);
-        let mut gpgs = GpgSync::new(&pr, &gr, "test")?;
+        let mut gpgs = GpgSync::new(
+            &pr,
+            &gr,
+            backend(),
+            passphrase("test"),
+            &SyncOptions::default(),
+            2,
+        )
+        .unwrap();
 
         assert!(!gr.join("notes.txt.gpg").exists());
 
         make_file(&pr.join("notes.txt"), b"hello");
         poll_predicate(
             &mut || {
-                gpgs.try_process_events(Duration::new(0, 200_000_000))?;
+                gpgs.try_process_events(Duration::new(0, 200_000_000))
+                    .unwrap();
 
                 gr.join("notes.txt.gpg").exists()
             },
@@ -510,12 +1323,348 @@ mod test {
         let (pr, gr) = test_roots("test_changed_gpgroot");
         init_dirs(&pr, &gr);
         make_file(&pr.join("notes.txt"), b"hello");
-        let gpgs = GpgSync::new(&pr, &gr, "test")?;
+        let gpgs = GpgSync::new(
+            &pr,
+            &gr,
+            backend(),
+            passphrase("test"),
+            &SyncOptions::default(),
+            2,
+        )
+        .unwrap();
         assert!(gr.join("notes.txt.gpg").exists());
         std::mem::drop(gpgs);
 
         let (_, gr2) = test_roots("test_changed_gpgroot2");
         init_dir(&gr2);
-        let _gpgs = GpgSync::new(&pr, &gr2, "test")?;
+        let _gpgs = GpgSync::new(
+            &pr,
+            &gr2,
+            backend(),
+            passphrase("test"),
+            &SyncOptions::default(),
+            2,
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_long_names() {
+        let (pr, gr) = test_roots("test_long_names");
+        init_dirs(&pr, &gr);
+        let long_name = "x".repeat(200);
+        std::fs::create_dir(pr.join(&long_name)).unwrap();
+        make_file(&pr.join(&long_name).join("notes.txt"), b"hello");
+        let options = SyncOptions {
+            encrypt_names: true,
+            ..SyncOptions::default()
+        };
+        let gpgs = GpgSync::new(&pr, &gr, backend(), passphrase("test"), &options, 2).unwrap();
+        std::mem::drop(gpgs);
+
+        // stored once, for the directory that was pushed
+        let long_names = gr.join(".gpgsync-names");
+        assert_eq!(std::fs::read_dir(&long_names).unwrap().count(), 1);
+
+        // and enough for another plain root
+        let (pr2, _) = test_roots("test_long_names2");
+        init_dir(&pr2);
+        let _gpgs = GpgSync::new(&pr2, &gr, backend(), passphrase("test"), &options, 2).unwrap();
+        assert_eq!(
+            std::fs::read(pr2.join(&long_name).join("notes.txt")).unwrap(),
+            b"hello"
+        );
+    }
+
+    #[test]
+    fn test_migrate_unescaped_names() {
+        let (pr, gr) = test_roots("test_migrate_unescaped_names");
+        init_dirs(&pr, &gr);
+        std::fs::create_dir(pr.join("x.gpg")).unwrap();
+        make_file(&pr.join("backup.gpg"), b"backup");
+        make_file(&pr.join("backup.gpg_"), b"other backup");
+        make_file(&pr.join("x.gpg").join("notes.txt"), b"notes");
+        let gpgs = GpgSync::new(
+            &pr,
+            &gr,
+            backend(),
+            passphrase("test"),
+            &SyncOptions::default(),
+            2,
+        )
+        .unwrap();
+        std::mem::drop(gpgs);
+
+        // turn it into a gpg root, base versions and database of version 2
+        let base = pr.join(".gpgsync-base");
+        for dir in &[&gr, &base] {
+            std::fs::rename(dir.join("x.gpg_"), dir.join("x.gpg")).unwrap();
+            std::fs::rename(dir.join("backup.gpg_.gpg"), dir.join("backup.gpg.gpg")).unwrap();
+            std::fs::rename(dir.join("backup.gpg__.gpg"), dir.join("backup.gpg_.gpg")).unwrap();
+        }
+        let db_path = pr.join(DB_FILENAME);
+        let mut db: serde_json::Value =
+            serde_json::from_slice(&std::fs::read(&db_path).unwrap()).unwrap();
+        db["db_version"] = 2.into();
+        std::fs::write(&db_path, db.to_string()).unwrap();
+
+        let _gpgs = GpgSync::new(
+            &pr,
+            &gr,
+            backend(),
+            passphrase("test"),
+            &SyncOptions::default(),
+            2,
+        )
+        .unwrap();
+        assert_eq!(std::fs::read(pr.join("backup.gpg")).unwrap(), b"backup");
+        assert_eq!(
+            std::fs::read(pr.join("backup.gpg_")).unwrap(),
+            b"other backup"
+        );
+        assert_eq!(
+            std::fs::read(pr.join("x.gpg").join("notes.txt")).unwrap(),
+            b"notes"
+        );
+        for dir in &[&gr, &base] {
+            let mut names = std::fs::read_dir(dir)
+                .unwrap()
+                .map(|e| e.unwrap().file_name().into_string().unwrap())
+                .collect::<Vec<_>>();
+            names.sort();
+            assert_eq!(names, vec!["backup.gpg_.gpg", "backup.gpg__.gpg", "x.gpg_"]);
+            assert!(dir.join("x.gpg_").join("notes.txt.gpg").exists());
+        }
+    }
+
+    #[test]
+    fn test_load_corrupt_db() {
+        let (pr, gr) = test_roots("test_load_corrupt_db");
+        init_dirs(&pr, &gr);
+        let db_path = pr.join(DB_FILENAME);
+        SyncDb::new(&gr).save_db(&db_path).unwrap();
+        SyncDb::new(&gr).save_db(&db_path).unwrap();
+        std::fs::write(&db_path, b"{\"db_vers").unwrap();
+
+        // recovered from the backup, the corrupt database is moved aside
+        assert_eq!(load_db(&db_path, &gr, "gpg").unwrap().gpg_root(), gr);
+        assert!(!db_path.exists());
+        assert_eq!(
+            std::fs::read(pr.join(".gpgsyncdb.corrupt")).unwrap(),
+            b"{\"db_vers"
+        );
+    }
+
+    #[test]
+    fn test_load_db_of_unsupported_version() {
+        let (pr, gr) = test_roots("test_load_db_of_unsupported_version");
+        init_dirs(&pr, &gr);
+        let db_path = pr.join(DB_FILENAME);
+        SyncDb::new(&gr).save_db(&db_path).unwrap();
+        std::fs::write(&db_path, b"{\"db_version\": 99}").unwrap();
+
+        // neither replaced by the backup nor moved aside
+        assert!(load_db(&db_path, &gr, "gpg").is_err());
+        assert_eq!(std::fs::read(&db_path).unwrap(), b"{\"db_version\": 99}");
+        assert!(!pr.join(".gpgsyncdb.corrupt").exists());
+    }
+}
+
+/// Tests of the file watcher with the age backend, which doesn't need a GnuPG
+/// installation.
+#[cfg(all(test, unix, feature = "age"))]
+mod watcher_test {
+    use super::{
+        AgeBackend, CryptoBackend, EncryptionPolicy, GpgSync, SyncOptions,
+        WATCHER_DEBOUNCE_DURATION,
+    };
+
+    use std::os::unix::fs::MetadataExt;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, Instant};
+
+    /// A plain and a gpg root in a temporary directory, synced with a
+    /// generated age identity.
+    struct Fixture {
+        _tmp: tempfile::TempDir,
+        plain_root: PathBuf,
+        gpg_root: PathBuf,
+        identity_file: PathBuf,
+        policy: EncryptionPolicy,
+    }
+
+    impl Fixture {
+        fn new() -> Self {
+            use age::secrecy::ExposeSecret;
+
+            let tmp = tempfile::tempdir().unwrap();
+            let dir = std::fs::canonicalize(tmp.path()).unwrap();
+            let (plain_root, gpg_root) = (dir.join("plain"), dir.join("gpg"));
+            std::fs::create_dir(&plain_root).unwrap();
+            std::fs::create_dir(&gpg_root).unwrap();
+
+            let identity = age::x25519::Identity::generate();
+            let identity_file = dir.join("identity");
+            std::fs::write(&identity_file, identity.to_string().expose_secret()).unwrap();
+            let policy = EncryptionPolicy::Recipients(vec![identity.to_public().to_string()]);
+
+            Self {
+                _tmp: tmp,
+                plain_root,
+                gpg_root,
+                identity_file,
+                policy,
+            }
+        }
+
+        fn backend(&self) -> AgeBackend {
+            AgeBackend::with_identity_files(std::slice::from_ref(&self.identity_file)).unwrap()
+        }
+
+        /// Performs the initial sync and starts the file watcher.
+        fn start(&self) -> GpgSync<AgeBackend> {
+            GpgSync::new(
+                &self.plain_root,
+                &self.gpg_root,
+                self.backend(),
+                self.policy.clone(),
+                &SyncOptions::default(),
+                2,
+            )
+            .unwrap()
+        }
+
+        fn plain(&self, rel: &str) -> PathBuf {
+            self.plain_root.join(rel)
+        }
+
+        fn gpg(&self, rel: &str) -> PathBuf {
+            self.gpg_root.join(rel)
+        }
+
+        fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+            let mut ciphertext = Vec::new();
+            self.backend()
+                .encrypt(&mut &plaintext[..], &mut ciphertext, &self.policy)
+                .unwrap();
+            ciphertext
+        }
+    }
+
+    /// Processes the events of the file watcher until `p` holds.
+    fn process_until(gpgs: &mut GpgSync<AgeBackend>, p: impl Fn() -> bool) {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !p() {
+            assert!(Instant::now() < deadline, "the sync didn't finish in time");
+            gpgs.try_process_events(Duration::from_millis(200)).unwrap();
+        }
+    }
+
+    /// Processes the events of the sync's own changes, which the debouncer
+    /// would otherwise merge with the next changes of a test.
+    fn settle(gpgs: &mut GpgSync<AgeBackend>) {
+        let deadline = Instant::now() + 2 * WATCHER_DEBOUNCE_DURATION;
+        while Instant::now() < deadline {
+            gpgs.try_process_events(Duration::from_millis(200)).unwrap();
+        }
+    }
+
+    fn ino(p: &Path) -> u64 {
+        std::fs::metadata(p).unwrap().ino()
+    }
+
+    #[test]
+    fn test_gpg_rename() {
+        let f = Fixture::new();
+        std::fs::write(f.plain("notes.txt"), b"hello").unwrap();
+        let mut gpgs = f.start();
+        let ino_before = ino(&f.plain("notes.txt"));
+
+        std::fs::rename(f.gpg("notes.txt.age"), f.gpg("renamed.txt.age")).unwrap();
+
+        process_until(&mut gpgs, || {
+            !f.plain("notes.txt").exists() && f.plain("renamed.txt").exists()
+        });
+        // renamed along, not decrypted anew
+        assert_eq!(ino(&f.plain("renamed.txt")), ino_before);
+    }
+
+    #[test]
+    fn test_dir_rename() {
+        let f = Fixture::new();
+        std::fs::create_dir_all(f.plain("docs/sub")).unwrap();
+        std::fs::write(f.plain("docs/a.txt"), b"a").unwrap();
+        std::fs::write(f.plain("docs/sub/b.txt"), b"b").unwrap();
+        let mut gpgs = f.start();
+        let ciphertext = std::fs::read(f.gpg("docs/sub/b.txt.age")).unwrap();
+
+        // on the plain side
+        std::fs::rename(f.plain("docs"), f.plain("papers")).unwrap();
+        process_until(&mut gpgs, || {
+            !f.gpg("docs").exists() && f.gpg("papers/sub/b.txt.age").exists()
+        });
+        assert!(f.gpg("papers/a.txt.age").exists());
+        assert_eq!(
+            std::fs::read(f.gpg("papers/sub/b.txt.age")).unwrap(),
+            ciphertext
+        );
+
+        // on the gpg side
+        settle(&mut gpgs);
+        let ino_before = ino(&f.plain("papers/sub/b.txt"));
+        std::fs::rename(f.gpg("papers"), f.gpg("letters")).unwrap();
+        process_until(&mut gpgs, || {
+            !f.plain("papers").exists() && f.plain("letters/sub/b.txt").exists()
+        });
+        assert!(f.plain("letters/a.txt").exists());
+        assert_eq!(ino(&f.plain("letters/sub/b.txt")), ino_before);
+    }
+
+    #[test]
+    fn test_directory_deletion() {
+        let f = Fixture::new();
+        std::fs::create_dir_all(f.plain("docs/sub")).unwrap();
+        std::fs::write(f.plain("docs/a.txt"), b"a").unwrap();
+        std::fs::write(f.plain("docs/sub/b.txt"), b"b").unwrap();
+        let mut gpgs = f.start();
+
+        // the other side modifies a file before the deletion is synced
+        std::fs::write(f.gpg("docs/sub/b.txt.age"), f.encrypt(b"b, changed")).unwrap();
+        std::fs::remove_dir_all(f.plain("docs")).unwrap();
+
+        process_until(&mut gpgs, || {
+            !f.gpg("docs/a.txt.age").exists() && f.plain("docs/sub/b.txt").exists()
+        });
+        assert_eq!(
+            std::fs::read(f.plain("docs/sub/b.txt")).unwrap(),
+            b"b, changed"
+        );
+        assert!(f.gpg("docs/sub/b.txt.age").exists());
+        assert!(!f.plain("docs/a.txt").exists());
+
+        // without modifications, the directories are removed on both sides
+        settle(&mut gpgs);
+        std::fs::remove_dir_all(f.gpg("docs")).unwrap();
+        process_until(&mut gpgs, || !f.plain("docs").exists());
+    }
+
+    #[test]
+    fn test_empty_dirs() {
+        let f = Fixture::new();
+        std::fs::create_dir_all(f.plain("plain_only/sub")).unwrap();
+        std::fs::create_dir(f.gpg("gpg_only")).unwrap();
+        let mut gpgs = f.start();
+        assert!(f.gpg("plain_only/sub").is_dir());
+        assert!(f.plain("gpg_only").is_dir());
+
+        std::fs::create_dir(f.plain("new")).unwrap();
+        process_until(&mut gpgs, || f.gpg("new").is_dir());
+
+        settle(&mut gpgs);
+        std::fs::remove_dir(f.gpg("new")).unwrap();
+        std::fs::remove_dir_all(f.plain("plain_only")).unwrap();
+        process_until(&mut gpgs, || {
+            !f.plain("new").exists() && !f.gpg("plain_only").exists()
+        });
     }
 }
@@ -1,19 +1,119 @@
 use std::path::PathBuf;
 use structopt::StructOpt;
 
+use anyhow::anyhow;
+use gpgsync::{CryptoBackend, EncryptionPolicy, HiddenFiles, PassphraseSource, SyncOptions};
 use notify_rust::Notification;
 
 #[derive(StructOpt)]
-struct Cli {
+struct Roots {
     /// The plaintext data path
     #[structopt(parse(from_os_str))]
     plain_root: PathBuf,
     /// The encrypted gpg path
     #[structopt(parse(from_os_str))]
     gpg_root: PathBuf,
-    /// The passphrase
-    passphrase: String,
 }
+
+#[derive(StructOpt)]
+struct CryptoArgs {
+    /// Where to read the passphrase for symmetric encryption from: "prompt", "stdin", "fd:N",
+    /// "file:PATH", "env:VAR" or "cmd:COMMAND"
+    #[structopt(long, default_value = "prompt")]
+    passphrase: PassphraseSource,
+    /// The passphrase itself.  Insecure, it is visible to other users and in the shell history
+    #[structopt(long, conflicts_with = "recipients")]
+    insecure_passphrase_arg: Option<String>,
+    /// Encrypt to the key with this fingerprint instead of using a passphrase (can be repeated)
+    #[structopt(short, long = "recipient")]
+    recipients: Vec<String>,
+    /// The encryption tool to use, "gpg", "rpgp" (pure-Rust OpenPGP) or "age"
+    #[structopt(long, default_value = "gpg", possible_values = &["gpg", "rpgp", "age"])]
+    backend: String,
+    /// An age identity file to decrypt files encrypted to recipients (can be repeated)
+    #[structopt(long = "identity", parse(from_os_str))]
+    identities: Vec<PathBuf>,
+}
+
+#[derive(StructOpt)]
+enum Cli {
+    /// Sync the two directories, then keep watching them for changes
+    Sync {
+        #[structopt(flatten)]
+        roots: Roots,
+        #[structopt(flatten)]
+        crypto: CryptoArgs,
+        /// Number of files to en- or decrypt at the same time during the initial sync [default:
+        /// number of CPUs]
+        #[structopt(short, long)]
+        jobs: Option<usize>,
+        /// Exclude the paths matching this gitignore-style glob from the sync, in addition to
+        /// those in .gitignore and .gpgsyncignore files (can be repeated)
+        #[structopt(long = "exclude")]
+        excludes: Vec<String>,
+        /// Whether to "sync" hidden files, except for version control, editor and cloud client
+        /// files, or to "skip" all of them
+        #[structopt(long, default_value = "sync", possible_values = &["sync", "skip"])]
+        hidden_files: HiddenFiles,
+        /// Encrypt the names of the files and directories in the gpg root, which must be empty.
+        /// Later syncs detect encrypted names by the name key in the gpg root
+        #[structopt(long)]
+        encrypt_names: bool,
+    },
+    /// Re-encrypt all files in the gpg root with a new passphrase or for new recipients
+    Rekey {
+        #[structopt(flatten)]
+        roots: Roots,
+        #[structopt(flatten)]
+        crypto: CryptoArgs,
+        /// Where to read the new passphrase from, see --passphrase
+        #[structopt(long, default_value = "prompt")]
+        new_passphrase: PassphraseSource,
+        /// The new passphrase itself.  Insecure, it is visible to other users and in the shell
+        /// history
+        #[structopt(long, conflicts_with = "new_recipients")]
+        insecure_new_passphrase_arg: Option<String>,
+        /// Encrypt to the key with this fingerprint from now on (can be repeated)
+        #[structopt(long = "new-recipient")]
+        new_recipients: Vec<String>,
+    },
+    /// Interactively resolve the conflicts found by the sync
+    Resolve {
+        #[structopt(flatten)]
+        roots: Roots,
+        #[structopt(flatten)]
+        crypto: CryptoArgs,
+    },
+}
+
+impl CryptoArgs {
+    fn backend(&self) -> anyhow::Result<Box<dyn CryptoBackend>> {
+        match self.backend.as_str() {
+            #[cfg(feature = "gpgme")]
+            "gpg" => Ok(Box::new(gpgsync::GpgmeBackend::new())),
+            #[cfg(feature = "pgp")]
+            "rpgp" => Ok(Box::new(gpgsync::RpgpBackend)),
+            #[cfg(feature = "age")]
+            "age" => Ok(Box::new(gpgsync::AgeBackend::with_identity_files(
+                &self.identities,
+            )?)),
+            other => Err(anyhow!("backend {} is not available in this build", other)),
+        }
+    }
+
+    fn policy(self) -> anyhow::Result<EncryptionPolicy> {
+        if !self.recipients.is_empty() {
+            return Ok(EncryptionPolicy::Recipients(self.recipients));
+        }
+        match self.insecure_passphrase_arg {
+            Some(passphrase) => Ok(EncryptionPolicy::Passphrase(passphrase.into())),
+            None => Ok(EncryptionPolicy::Passphrase(
+                self.passphrase.read("Passphrase: ")?,
+            )),
+        }
+    }
+}
+
 fn desktop_notify(msg: &str) {
     Notification::new()
         .summary("GPGSync crashed")
@@ -24,23 +124,76 @@ fn desktop_notify(msg: &str) {
         .unwrap();
 }
 
-fn main() {
-    let args = Cli::from_args();
-
-    match gpgsync::GpgSync::new(&args.plain_root, &args.gpg_root, &args.passphrase) {
-        Ok(mut gpg_sync) => loop {
-            if let anyhow::Result::Err(e) =
-                gpg_sync.try_process_events(std::time::Duration::new(1, 0))
-            {
-                println!("{:?}", e);
-                desktop_notify(&e.to_string());
-                break;
+fn run(cli: Cli) -> anyhow::Result<()> {
+    match cli {
+        Cli::Sync {
+            roots,
+            crypto,
+            jobs,
+            excludes,
+            hidden_files,
+            encrypt_names,
+        } => {
+            let backend = crypto.backend()?;
+            let jobs = match jobs {
+                Some(jobs) => jobs,
+                None => std::thread::available_parallelism()?.get(),
+            };
+            let mut gpg_sync = gpgsync::GpgSync::new(
+                &roots.plain_root,
+                &roots.gpg_root,
+                backend,
+                crypto.policy()?,
+                &SyncOptions {
+                    exclude: excludes,
+                    hidden_files,
+                    encrypt_names,
+                },
+                jobs,
+            )?;
+            loop {
+                gpg_sync.try_process_events(std::time::Duration::new(1, 0))?;
             }
-        },
-        Err(e) => {
-            println!("{:?}", e);
-            desktop_notify(&e.to_string());
         }
+        Cli::Rekey {
+            roots,
+            crypto,
+            new_passphrase,
+            insecure_new_passphrase_arg,
+            new_recipients,
+        } => {
+            let backend = crypto.backend()?;
+            let new_policy = if !new_recipients.is_empty() {
+                EncryptionPolicy::Recipients(new_recipients)
+            } else if let Some(passphrase) = insecure_new_passphrase_arg {
+                EncryptionPolicy::Passphrase(passphrase.into())
+            } else {
+                EncryptionPolicy::Passphrase(new_passphrase.read_new("New passphrase: ")?)
+            };
+            gpgsync::rekey(
+                &roots.plain_root,
+                &roots.gpg_root,
+                &backend,
+                &crypto.policy()?,
+                &new_policy,
+            )
+        }
+        Cli::Resolve { roots, crypto } => {
+            let backend = crypto.backend()?;
+            gpgsync::resolve(
+                &roots.plain_root,
+                &roots.gpg_root,
+                &backend,
+                crypto.policy()?,
+            )
+        }
+    }
+}
+
+fn main() {
+    if let Err(e) = run(Cli::from_args()) {
+        println!("{:?}", e);
+        desktop_notify(&e.to_string());
     }
 
     // if let std::thread::Result::Err(err) = result {
@@ -0,0 +1,208 @@
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use crate::backend::CryptoBackend;
+use crate::fileutils;
+use crate::policy::EncryptionPolicy;
+use crate::secret::SecretBuf;
+use crate::syncentity::{SyncEntity, SyncRoots};
+
+/// Name of the directory in the plain root holding the base versions.
+///
+/// It is in the plain root, because each plain directory has its own last
+/// synced version of a file.  The base versions are encrypted nevertheless,
+/// so that no old plaintext versions linger around.
+pub const BASE_DIRNAME: &str = ".gpgsync-base";
+
+/// Text files up to this size are kept as a base for merges.
+const MAX_BASE_SIZE: u64 = 1 << 20;
+
+/// Path of the base version of `se`, the encrypted version it had when it
+/// was last in sync.
+fn base_path(se: &SyncEntity) -> PathBuf {
+    let gpg_path = se.as_gpg();
+    let rel = gpg_path.strip_prefix(&se.roots().gpg_root).unwrap();
+    se.roots().plain_root.join(BASE_DIRNAME).join(rel)
+}
+
+/// Path of the directory holding the base versions of the entities below the
+/// directory `rel`.
+pub fn base_dir(roots: &SyncRoots, rel: &Path) -> PathBuf {
+    let gpg_dir = roots.gpg_path(rel, false);
+    let rel = gpg_dir.strip_prefix(&roots.gpg_root).unwrap();
+    roots.plain_root.join(BASE_DIRNAME).join(rel)
+}
+
+/// Returns the contents of the file at `p` if it is a text file that is
+/// small enough to be merged.
+fn read_text(p: &std::path::Path) -> io::Result<Option<String>> {
+    let f = fileutils::open_read(p)?;
+    if f.metadata()?.len() > MAX_BASE_SIZE {
+        return Ok(None);
+    }
+
+    let mut contents = Vec::new();
+    f.take(MAX_BASE_SIZE).read_to_end(&mut contents)?;
+    Ok(String::from_utf8(contents)
+        .ok()
+        .filter(|text| !text.contains('\0')))
+}
+
+fn decrypt_text(
+    p: &std::path::Path,
+    backend: &dyn CryptoBackend,
+    policy: &EncryptionPolicy,
+) -> io::Result<SecretBuf> {
+    let mut decrypted = SecretBuf::new();
+    backend.decrypt(&mut fileutils::open_read(p)?, &mut decrypted, policy)?;
+    Ok(decrypted)
+}
+
+/// Stores the version of `se` that was just synced as the base for future
+/// merges, if it is a text file.  Both files must have the same contents.
+pub fn store_base(se: &SyncEntity) -> io::Result<()> {
+    if read_text(&se.as_plain())?.is_none() {
+        return remove_base(se);
+    }
+
+    // the gpg file is the encrypted base already
+    let mut gpg_f = fileutils::open_read(&se.as_gpg())?;
+    fileutils::write_atomically(&base_path(se), |f| io::copy(&mut gpg_f, f).map(|_| ()))
+}
+
+pub fn remove_base(se: &SyncEntity) -> io::Result<()> {
+    match std::fs::remove_file(base_path(se)) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Moves the base version of `from` to be the one of `to`.
+pub fn rename_base(from: &SyncEntity, to: &SyncEntity) -> io::Result<()> {
+    let (from, to) = (base_path(from), base_path(to));
+    if !from.exists() {
+        return Ok(());
+    }
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::rename(from, to)
+}
+
+/// Moves the base versions below the directory `from` to below `to`.
+pub fn rename_base_dir(roots: &SyncRoots, from: &Path, to: &Path) -> io::Result<()> {
+    let (from, to) = (base_dir(roots, from), base_dir(roots, to));
+    if !from.exists() {
+        return Ok(());
+    }
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::rename(from, to)
+}
+
+/// Tries a three-way merge of the two differing versions of the text file
+/// `se` with their base.
+///
+/// If the changes on both sides don't overlap, the merged version is written
+/// to both sides and `true` is returned.  Otherwise nothing is changed.
+pub fn try_merge(
+    se: &SyncEntity,
+    backend: &dyn CryptoBackend,
+    policy: &EncryptionPolicy,
+) -> io::Result<bool> {
+    let base_path = base_path(se);
+    if !base_path.exists() {
+        return Ok(false);
+    }
+    let plain = match read_text(&se.as_plain())? {
+        Some(plain) => plain,
+        None => return Ok(false),
+    };
+    let (base, gpg) = match (
+        decrypt_text(&base_path, backend, policy),
+        decrypt_text(&se.as_gpg(), backend, policy)?,
+    ) {
+        (Ok(base), gpg) => (base, gpg),
+        (Err(e), _) => {
+            // e. g. after a rekey
+            println!("could not decrypt the base of {:?}: {}", &se, e);
+            return Ok(false);
+        }
+    };
+    let (base, gpg) = match (
+        std::str::from_utf8(base.as_slice()),
+        std::str::from_utf8(gpg.as_slice()),
+    ) {
+        (Ok(base), Ok(gpg)) => (base, gpg),
+        _ => return Ok(false),
+    };
+
+    let merged = match diffy::merge(base, &plain, gpg) {
+        Ok(merged) => merged,
+        Err(_) => return Ok(false),
+    };
+
+    println!("merged the changes to {:?}", &se);
+    fileutils::write_atomically(&se.as_plain(), |f| f.write_all(merged.as_bytes()))?;
+    crate::push_plain(se, backend, policy)?;
+    store_base(se)?;
+
+    Ok(true)
+}
+
+#[cfg(all(test, feature = "age"))]
+mod test {
+    use super::{store_base, try_merge};
+    use crate::policy::EncryptionPolicy;
+    use crate::syncentity::{SyncEntity, SyncRoots};
+    use crate::{push_gpg, push_plain, AgeBackend};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_try_merge() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let roots = Arc::new(SyncRoots {
+            plain_root: dir.join("plain"),
+            gpg_root: dir.join("gpg"),
+            extension: "age",
+            name_cipher: None,
+        });
+        std::fs::create_dir_all(&roots.plain_root).unwrap();
+
+        let identity = age::x25519::Identity::generate();
+        let identity_file = dir.join("identity");
+        {
+            use age::secrecy::ExposeSecret;
+            std::fs::write(&identity_file, identity.to_string().expose_secret()).unwrap();
+        }
+        let backend = AgeBackend::with_identity_files(&[identity_file]).unwrap();
+        let policy = EncryptionPolicy::Recipients(vec![identity.to_public().to_string()]);
+
+        let se = SyncEntity::from_rel(&PathBuf::from("notes.org"), &roots);
+        std::fs::write(se.as_plain(), "* one\n\n* two\n\n* three\n").unwrap();
+        push_plain(&se, &backend, &policy).unwrap();
+        store_base(&se).unwrap();
+
+        // the other side changes the first paragraph
+        std::fs::write(se.as_plain(), "* one, changed\n\n* two\n\n* three\n").unwrap();
+        push_plain(&se, &backend, &policy).unwrap();
+        // this side changes the last one
+        std::fs::write(se.as_plain(), "* one\n\n* two\n\n* three, changed\n").unwrap();
+
+        assert!(try_merge(&se, &backend, &policy).unwrap());
+        let merged = "* one, changed\n\n* two\n\n* three, changed\n";
+        assert_eq!(std::fs::read_to_string(se.as_plain()).unwrap(), merged);
+        std::fs::remove_file(se.as_plain()).unwrap();
+        push_gpg(&se, &backend, &policy).unwrap();
+        assert_eq!(std::fs::read_to_string(se.as_plain()).unwrap(), merged);
+
+        // overlapping changes can't be merged
+        std::fs::write(se.as_plain(), "* one\n\n* two\n\n* three, again\n").unwrap();
+        push_plain(&se, &backend, &policy).unwrap();
+        std::fs::write(se.as_plain(), "* one\n\n* two\n\n* three, differently\n").unwrap();
+        assert!(!try_merge(&se, &backend, &policy).unwrap());
+    }
+}
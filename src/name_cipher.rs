@@ -0,0 +1,383 @@
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use aes_siv::siv::Aes256Siv;
+use aes_siv::KeyInit;
+use anyhow::{anyhow, Context};
+use data_encoding::{Encoding, Specification};
+use lazy_static::lazy_static;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+
+use crate::backend::CryptoBackend;
+use crate::policy::{EncryptionPolicy, PolicySet};
+use crate::secret::SecretBuf;
+use crate::{fileutils, names};
+
+/// File name of the name key in the gpg root, without the extension of the
+/// ciphertexts.
+const KEY_FILENAME: &str = ".gpgsync-namekey";
+
+/// Length of the name key, AES-SIV uses two AES-256 keys.
+const KEY_LEN: usize = 64;
+
+/// Name of the directory in the gpg root that holds the encrypted names that
+/// are too long to be used as file names.
+const LONG_NAMES_DIRNAME: &str = ".gpgsync-names";
+
+/// Maximum length of a file name on common file systems.
+const MAX_NAME_LEN: usize = 255;
+
+/// Starts the names in the gpg root that stand for a long name.  It is not
+/// part of the base32 alphabet.
+const LONG_NAME_PREFIX: &str = "_";
+
+lazy_static! {
+    /// Lowercase base32, because some file systems ignore the case.
+    static ref BASE32: Encoding = {
+        let mut spec = Specification::new();
+        spec.symbols.push_str("abcdefghijklmnopqrstuvwxyz234567");
+        spec.encoding().unwrap()
+    };
+}
+
+/// Encrypts the names of files and directories in the gpg root.
+///
+/// Each name is encrypted on its own with AES-SIV, which is deterministic, so
+/// a name always gets the same encrypted name and a renamed file or directory
+/// can simply be renamed on the other side.  The flip side is that equal names
+/// in different directories have equal encrypted names.  The ciphertext is
+/// encoded as lowercase base32, files get the extension of the ciphertexts
+/// appended.
+///
+/// If that would exceed 255 bytes, i. e. for names longer than about 130
+/// bytes, the encrypted name is stored in a file in `.gpgsync-names` instead,
+/// named by its hash, and the name in the gpg root is `_` and the hash.  It is
+/// stored when a file or directory with that name is created in the gpg root.
+/// These files are never removed, another machine may still need them.
+///
+/// The key is random and stored in the gpg root, encrypted like the files.
+pub struct NameCipher {
+    key: SecretBuf,
+    long_names_dir: PathBuf,
+}
+
+impl NameCipher {
+    fn new(key: SecretBuf, gpg_root: &Path) -> Self {
+        Self {
+            key,
+            long_names_dir: gpg_root.join(LONG_NAMES_DIRNAME),
+        }
+    }
+
+    /// Returns the name cipher with the name key stored in `gpg_root`, if
+    /// there is one.  With `create`, a new name key is stored in `gpg_root` if
+    /// it is empty.  The key is en- and decrypted by `backend` according to
+    /// `policy`.
+    pub fn load(
+        gpg_root: &Path,
+        backend: &dyn CryptoBackend,
+        policy: &EncryptionPolicy,
+        create: bool,
+    ) -> anyhow::Result<Option<Self>> {
+        let key_path = key_path(gpg_root, backend.extension());
+        let mut key = SecretBuf::new();
+        if key_path.exists() {
+            backend
+                .decrypt(&mut fileutils::open_read(&key_path)?, &mut key, policy)
+                .with_context(|| format!("could not decrypt the name key {:?}", key_path))?;
+            if key.as_slice().len() != KEY_LEN {
+                return Err(anyhow!("invalid name key {:?}", key_path));
+            }
+        } else if create {
+            if std::fs::read_dir(gpg_root)?.next().is_some() {
+                return Err(anyhow!(
+                    "names can only be encrypted in an empty gpg directory, {:?} is not",
+                    gpg_root
+                ));
+            }
+            println!("creating the name key {:?}", key_path);
+            let mut bytes = [0; KEY_LEN];
+            rand::rngs::OsRng.fill_bytes(&mut bytes);
+            key.write_all(&bytes)?;
+            zeroize::Zeroize::zeroize(&mut bytes);
+            fileutils::write_atomically(&key_path, |f| {
+                backend.encrypt(&mut key.as_slice(), f, policy)
+            })?;
+        } else {
+            return Ok(None);
+        }
+
+        Ok(Some(Self::new(key, gpg_root)))
+    }
+
+    fn siv(&self) -> Aes256Siv {
+        Aes256Siv::new_from_slice(self.key.as_slice()).unwrap()
+    }
+
+    fn encrypt(&self, name: &OsStr) -> Vec<u8> {
+        self.siv()
+            .encrypt(no_headers(), &names::to_bytes(name))
+            .unwrap()
+    }
+
+    /// Returns the name in the gpg root of the file or directory called `name`
+    /// in the plain root, see `names::gpg_name()`.
+    ///
+    /// A long name can only be mapped back once it is stored by
+    /// `store_long_name()`.
+    pub fn gpg_name(&self, name: &OsStr, extension: &str, is_file: bool) -> OsString {
+        let ciphertext = self.encrypt(name);
+        let mut gpg_name = match long_name_hash(&ciphertext, extension, is_file) {
+            Some(hash) => format!("{}{}", LONG_NAME_PREFIX, hash),
+            None => BASE32.encode(&ciphertext),
+        };
+        if is_file {
+            gpg_name.push('.');
+            gpg_name.push_str(extension);
+        }
+        gpg_name.into()
+    }
+
+    /// Returns the name in the plain root that `gpg_name()` maps to `name`,
+    /// or `None` if there is none, or if it is a long name that is not
+    /// available yet.
+    pub fn plain_name(&self, name: &OsStr, extension: &str, is_file: bool) -> Option<OsString> {
+        let name = name.to_str()?;
+        let encoded = if is_file {
+            name.strip_suffix(extension)?.strip_suffix('.')?
+        } else {
+            name
+        };
+        let suffix_len = suffix_len(extension, is_file);
+
+        let ciphertext = match encoded.strip_prefix(LONG_NAME_PREFIX) {
+            Some(hash) => {
+                let ciphertext = self.load_long_name(hash)?;
+                if BASE32.encode_len(ciphertext.len()) + suffix_len <= MAX_NAME_LEN {
+                    return None;
+                }
+                ciphertext
+            }
+            None => {
+                if encoded.len() + suffix_len > MAX_NAME_LEN {
+                    return None;
+                }
+                BASE32.decode(encoded.as_bytes()).ok()?
+            }
+        };
+
+        let plain = self.siv().decrypt(no_headers(), &ciphertext).ok()?;
+        if plain.is_empty() || plain == b"." || plain == b".." || plain.contains(&b'/') {
+            return None;
+        }
+        Some(names::from_bytes(plain))
+    }
+
+    /// Returns whether `p` is the file of a long name.
+    pub fn is_long_name(&self, p: &Path) -> bool {
+        p.parent() == Some(&self.long_names_dir)
+    }
+
+    /// Stores the encrypted name of `name` if it is a long name, so that
+    /// `plain_name()` can map it back, here and on other machines.  Has to be
+    /// called before a file or directory of that name is created in the gpg
+    /// root.
+    pub fn store_long_name(&self, name: &OsStr, extension: &str, is_file: bool) -> io::Result<()> {
+        let ciphertext = self.encrypt(name);
+        let hash = match long_name_hash(&ciphertext, extension, is_file) {
+            Some(hash) => hash,
+            None => return Ok(()),
+        };
+        let p = self.long_names_dir.join(hash);
+        if !p.exists() {
+            std::fs::create_dir_all(&self.long_names_dir)?;
+            fileutils::write_atomically(&p, |f| f.write_all(&ciphertext))?;
+        }
+        Ok(())
+    }
+
+    fn load_long_name(&self, hash: &str) -> Option<Vec<u8>> {
+        // also makes sure that the hash is no path
+        if BASE32.decode(hash.as_bytes()).ok()?.len() != Sha256::output_size() {
+            return None;
+        }
+        let ciphertext = std::fs::read(self.long_names_dir.join(hash)).ok()?;
+        if BASE32.encode(&Sha256::digest(&ciphertext)) != hash {
+            return None;
+        }
+        Some(ciphertext)
+    }
+}
+
+// only one name cipher is used at a time, so these just have to be consistent
+impl std::fmt::Debug for NameCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NameCipher")
+            .field("long_names_dir", &self.long_names_dir)
+            .finish()
+    }
+}
+
+/// Path of the name key in `gpg_root`.
+pub fn key_path(gpg_root: &Path, extension: &str) -> PathBuf {
+    gpg_root.join(format!("{}.{}", KEY_FILENAME, extension))
+}
+
+/// Re-encrypts the name key in `gpg_root`, if there is one, for the
+/// `new_policies`, see `PolicySet::name_key_policy()`.  A key that was
+/// re-encrypted already is left alone.
+pub fn rekey_name_key(
+    gpg_root: &Path,
+    backend: &dyn CryptoBackend,
+    old_policies: &PolicySet,
+    new_policies: &PolicySet,
+) -> anyhow::Result<()> {
+    let key_path = key_path(gpg_root, backend.extension());
+    if !key_path.exists() {
+        return Ok(());
+    }
+    let old_policy = &old_policies.name_key_policy()?;
+    let new_policy = &new_policies.name_key_policy()?;
+
+    let mut key = SecretBuf::new();
+    match backend.decrypt(&mut fileutils::open_read(&key_path)?, &mut key, old_policy) {
+        Ok(()) => {
+            println!("rekeying {:?}", &key_path);
+            fileutils::write_atomically(&key_path, |f| {
+                backend.encrypt(&mut key.as_slice(), f, new_policy)
+            })?;
+        }
+        Err(e) => {
+            let mut f = fileutils::open_read(&key_path)?;
+            if backend
+                .decrypt(&mut f, &mut io::sink(), new_policy)
+                .is_err()
+            {
+                return Err(anyhow!("could not decrypt {:?}: {}", &key_path, e));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn no_headers() -> std::iter::Empty<&'static [u8]> {
+    std::iter::empty()
+}
+
+/// Returns the hash that stands for the encrypted name `ciphertext` in the gpg
+/// root, if the encoded name is too long to be used there.
+fn long_name_hash(ciphertext: &[u8], extension: &str, is_file: bool) -> Option<String> {
+    if BASE32.encode_len(ciphertext.len()) + suffix_len(extension, is_file) > MAX_NAME_LEN {
+        Some(BASE32.encode(&Sha256::digest(ciphertext)))
+    } else {
+        None
+    }
+}
+
+/// Length of what is appended to the encoded name in the gpg root.
+fn suffix_len(extension: &str, is_file: bool) -> usize {
+    if is_file {
+        extension.len() + 1
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{NameCipher, LONG_NAMES_DIRNAME, MAX_NAME_LEN};
+    use crate::fileutils;
+    use crate::secret::SecretBuf;
+    use proptest::prelude::*;
+    use std::ffi::{OsStr, OsString};
+    use std::io::Write;
+    use std::path::Path;
+
+    fn name_cipher(dir: &Path) -> NameCipher {
+        let mut key = SecretBuf::new();
+        key.write_all(&[7; super::KEY_LEN]).unwrap();
+        NameCipher::new(key, dir)
+    }
+
+    fn round_trip(cipher: &NameCipher, name: &str, is_file: bool) -> OsString {
+        cipher
+            .store_long_name(OsStr::new(name), "age", is_file)
+            .unwrap();
+        let gpg_name = cipher.gpg_name(OsStr::new(name), "age", is_file);
+        assert!(gpg_name.len() <= MAX_NAME_LEN);
+        assert_eq!(
+            cipher.plain_name(&gpg_name, "age", is_file),
+            Some(OsString::from(name))
+        );
+        gpg_name
+    }
+
+    #[test]
+    fn test_name_cipher() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let cipher = name_cipher(dir);
+
+        let gpg_name = round_trip(&cipher, "diary 2026.txt", true);
+        let gpg_name = gpg_name.to_str().unwrap();
+        assert!(gpg_name.ends_with(".age"));
+        assert!(!gpg_name.contains("diary"));
+        assert_eq!(
+            cipher.gpg_name(OsStr::new("diary 2026.txt"), "age", true),
+            OsStr::new(gpg_name)
+        );
+        let dir_name = round_trip(&cipher, "diary 2026.txt", false);
+        assert_ne!(dir_name, OsStr::new(gpg_name));
+
+        // not written by gpg_name
+        assert_eq!(
+            cipher.plain_name(OsStr::new("notes.txt"), "age", true),
+            None
+        );
+        assert_eq!(
+            cipher.plain_name(OsStr::new(&gpg_name.to_uppercase()), "age", true),
+            None
+        );
+        let first = if gpg_name.starts_with('a') { "b" } else { "a" };
+        let tampered = format!("{}{}", first, &gpg_name[1..]);
+        assert_eq!(cipher.plain_name(OsStr::new(&tampered), "age", true), None);
+
+        // long names are stored in files, only when asked to
+        assert!(!dir.join(LONG_NAMES_DIRNAME).exists());
+        let long_name = "x".repeat(255);
+        let gpg_name = cipher.gpg_name(OsStr::new(&long_name), "age", true);
+        assert!(gpg_name.to_str().unwrap().starts_with('_'));
+        assert_eq!(cipher.plain_name(&gpg_name, "age", true), None);
+        assert!(!dir.join(LONG_NAMES_DIRNAME).exists());
+        assert_eq!(round_trip(&cipher, &long_name, true), gpg_name);
+        assert!(dir.join(LONG_NAMES_DIRNAME).read_dir().unwrap().count() == 1);
+        let other = name_cipher(dir);
+        assert_eq!(
+            other.plain_name(&gpg_name, "age", true),
+            Some(OsString::from(&long_name))
+        );
+        assert_eq!(other.plain_name(OsStr::new("_..age"), "age", true), None);
+
+        // names just short enough to be encrypted in place can be written,
+        // although the temporary file of the write has a name of its own
+        let gpg_name = round_trip(&cipher, &"y".repeat(140), true);
+        assert!(gpg_name.len() > MAX_NAME_LEN - 8);
+        assert!(!gpg_name.to_str().unwrap().starts_with('_'));
+        let gpg_path = dir.join(&gpg_name);
+        fileutils::write_atomically(&gpg_path, |f| f.write_all(b"ciphertext")).unwrap();
+        assert_eq!(std::fs::read(&gpg_path).unwrap(), b"ciphertext");
+    }
+
+    proptest! {
+        #[test]
+        fn test_name_cipher_round_trip(name in "[^/\\x00]{1,200}", is_file: bool) {
+            prop_assume!(name != "." && name != "..");
+            let dir = tempfile::tempdir().unwrap();
+            let cipher = name_cipher(dir.path());
+            round_trip(&cipher, &name, is_file);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use std::borrow::Cow;
+use std::ffi::{OsStr, OsString};
+
+/// Appended to names in the gpg root that would otherwise end in the
+/// extension of the ciphertexts.
+const ESCAPE: u8 = b'_';
+
+/// Returns the name in the gpg root of the file or directory called `name` in
+/// the plain root.
+///
+/// Files get the extension of the ciphertexts appended, e. g. `notes.txt`
+/// becomes `notes.txt.gpg`.  A name that already ends in the extension,
+/// possibly followed by underscores, is escaped by appending another
+/// underscore: the file `backup.gpg` becomes `backup.gpg_.gpg`, and the
+/// directory `backup.gpg` becomes `backup.gpg_`.  Thus the name of a directory
+/// never ends in the extension, so it can't collide with a file, and each
+/// name in the gpg root belongs to at most one name in the plain root.
+pub fn gpg_name(name: &OsStr, extension: &str, is_file: bool) -> OsString {
+    let mut bytes = to_bytes(name).into_owned();
+    if ends_with_extension(trim_escapes(&bytes), extension) {
+        bytes.push(ESCAPE);
+    }
+    if is_file {
+        bytes.push(b'.');
+        bytes.extend_from_slice(extension.as_bytes());
+    }
+    from_bytes(bytes)
+}
+
+/// Returns the name in the plain root that `gpg_name` maps to `name`, or
+/// `None` if there is none, e. g. for a file without the extension.
+pub fn plain_name(name: &OsStr, extension: &str, is_file: bool) -> Option<OsString> {
+    let bytes = to_bytes(name);
+    let mut bytes = if is_file {
+        if !ends_with_extension(&bytes, extension) {
+            return None;
+        }
+        bytes[..bytes.len() - extension.len() - 1].to_vec()
+    } else {
+        bytes.into_owned()
+    };
+
+    if ends_with_extension(trim_escapes(&bytes), extension) {
+        if bytes.last() != Some(&ESCAPE) {
+            return None;
+        }
+        bytes.pop();
+    }
+    if bytes.is_empty() || bytes == b"." || bytes == b".." {
+        return None;
+    }
+    Some(from_bytes(bytes))
+}
+
+/// Returns whether `name` ends in a dot and `extension`, after something else.
+fn ends_with_extension(name: &[u8], extension: &str) -> bool {
+    let extension = extension.as_bytes();
+    name.len() > extension.len() + 1
+        && name.ends_with(extension)
+        && name[name.len() - extension.len() - 1] == b'.'
+}
+
+fn trim_escapes(name: &[u8]) -> &[u8] {
+    let len = name.iter().rposition(|b| *b != ESCAPE).map_or(0, |i| i + 1);
+    &name[..len]
+}
+
+#[cfg(unix)]
+pub fn to_bytes(name: &OsStr) -> Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Cow::Borrowed(name.as_bytes())
+}
+
+#[cfg(unix)]
+pub fn from_bytes(bytes: Vec<u8>) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(bytes)
+}
+
+// names that aren't valid Unicode can't be mapped here, but names are only
+// ever split at ASCII characters, so valid ones stay valid
+#[cfg(not(unix))]
+pub fn to_bytes(name: &OsStr) -> Cow<'_, [u8]> {
+    match name.to_string_lossy() {
+        Cow::Borrowed(s) => Cow::Borrowed(s.as_bytes()),
+        Cow::Owned(s) => Cow::Owned(s.into_bytes()),
+    }
+}
+
+#[cfg(not(unix))]
+pub fn from_bytes(bytes: Vec<u8>) -> OsString {
+    String::from_utf8(bytes).unwrap().into()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{gpg_name, plain_name};
+    use std::ffi::OsStr;
+
+    fn gpg(name: &str, is_file: bool) -> String {
+        gpg_name(OsStr::new(name), "gpg", is_file)
+            .into_string()
+            .unwrap()
+    }
+
+    fn plain(name: &str, is_file: bool) -> Option<String> {
+        plain_name(OsStr::new(name), "gpg", is_file).map(|n| n.into_string().unwrap())
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!(gpg("notes.txt", true), "notes.txt.gpg");
+        assert_eq!(gpg("notes", false), "notes");
+        assert_eq!(gpg("backup.gpg", true), "backup.gpg_.gpg");
+        assert_eq!(gpg("backup.gpg", false), "backup.gpg_");
+        assert_eq!(gpg("backup.gpg_", false), "backup.gpg__");
+        assert_eq!(gpg("backup_", false), "backup_");
+        assert_eq!(gpg(".gpg", true), ".gpg.gpg");
+
+        assert_eq!(plain("notes.txt.gpg", true), Some("notes.txt".to_string()));
+        assert_eq!(
+            plain("backup.gpg_.gpg", true),
+            Some("backup.gpg".to_string())
+        );
+        assert_eq!(
+            plain("backup.gpg__", false),
+            Some("backup.gpg_".to_string())
+        );
+
+        // not written by gpg_name
+        assert_eq!(plain("notes.txt", true), None);
+        assert_eq!(plain(".gpg", true), None);
+        assert_eq!(plain("..gpg", true), None);
+        assert_eq!(plain("backup.gpg.gpg", true), None);
+        assert_eq!(plain("backup.gpg", false), None);
+    }
+}
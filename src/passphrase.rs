@@ -0,0 +1,174 @@
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use anyhow::anyhow;
+use zeroize::Zeroize;
+
+use crate::secret::{Secret, SecretBuf};
+
+/// Where a passphrase is read from.
+///
+/// Passphrases are never taken from the command line directly, where they
+/// would show up in the process list and the shell history.
+#[derive(Clone, Debug, PartialEq)]
+pub enum PassphraseSource {
+    /// Ask on the terminal, without echoing the input.
+    Prompt,
+    /// The first line of the standard input.
+    Stdin,
+    /// The first line read from an open file descriptor.
+    Fd(i32),
+    /// The first line of a file.
+    File(PathBuf),
+    /// The value of an environment variable.
+    Env(String),
+    /// The first line of the output of a shell command, e. g. `pass show gpgsync`.
+    Command(String),
+}
+
+/// Reads the first line, without the line ending.
+///
+/// Reads byte by byte instead of through a `BufReader`, which would leave a
+/// copy of the passphrase in its buffer.
+fn read_first_line(mut r: impl Read) -> io::Result<Secret> {
+    let mut line = SecretBuf::new();
+    let mut byte = [0u8; 1];
+    while r.read(&mut byte)? == 1 && byte[0] != b'\n' {
+        line.write_all(&byte)?;
+    }
+    byte.zeroize();
+
+    let line = line.as_slice();
+    Secret::from_slice(line.strip_suffix(b"\r").unwrap_or(line))
+}
+
+impl PassphraseSource {
+    /// Reads the passphrase.  `prompt` is shown if the user is asked for it.
+    pub fn read(&self, prompt: &str) -> io::Result<Secret> {
+        match self {
+            PassphraseSource::Prompt => rpassword::prompt_password(prompt).map(Secret::new),
+            PassphraseSource::Stdin => read_first_line(io::stdin()),
+            #[cfg(unix)]
+            PassphraseSource::Fd(fd) => {
+                use std::os::unix::io::FromRawFd;
+                // the descriptor is handed to us by the caller and not used otherwise
+                read_first_line(unsafe { File::from_raw_fd(*fd) })
+            }
+            #[cfg(not(unix))]
+            PassphraseSource::Fd(_) => Err(io::Error::other(
+                "reading from file descriptors is only supported on unix",
+            )),
+            PassphraseSource::File(p) => read_first_line(File::open(p)?),
+            PassphraseSource::Env(var) => std::env::var(var)
+                .map(Secret::new)
+                .map_err(|e| io::Error::new(io::ErrorKind::NotFound, format!("{}: {}", var, e))),
+            PassphraseSource::Command(cmd) => {
+                let mut output = std::process::Command::new("sh")
+                    .arg("-c")
+                    .arg(cmd)
+                    .stderr(std::process::Stdio::inherit())
+                    .output()?;
+                if !output.status.success() {
+                    return Err(io::Error::other(format!(
+                        "passphrase command failed: {}",
+                        output.status
+                    )));
+                }
+                let passphrase = read_first_line(&output.stdout[..]);
+                output.stdout.zeroize();
+                passphrase
+            }
+        }
+    }
+
+    /// Reads a new passphrase.  When asking the user, the passphrase has to be
+    /// entered twice so that a typo doesn't lock them out.
+    pub fn read_new(&self, prompt: &str) -> io::Result<Secret> {
+        let passphrase = self.read(prompt)?;
+        if *self == PassphraseSource::Prompt
+            && self.read("Repeat passphrase: ")?.expose() != passphrase.expose()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the passphrases don't match",
+            ));
+        }
+        Ok(passphrase)
+    }
+}
+
+impl FromStr for PassphraseSource {
+    type Err = anyhow::Error;
+
+    /// Parses "prompt", "stdin", "fd:N", "file:PATH", "env:VAR" or "cmd:COMMAND".
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (kind, arg) = match s.find(':') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        match (kind, arg) {
+            ("prompt", None) => Ok(PassphraseSource::Prompt),
+            ("stdin", None) => Ok(PassphraseSource::Stdin),
+            ("fd", Some(fd)) => Ok(PassphraseSource::Fd(fd.parse()?)),
+            ("file", Some(p)) => Ok(PassphraseSource::File(PathBuf::from(p))),
+            ("env", Some(var)) => Ok(PassphraseSource::Env(var.to_string())),
+            ("cmd", Some(cmd)) => Ok(PassphraseSource::Command(cmd.to_string())),
+            _ => Err(anyhow!(
+                "invalid passphrase source {:?}, expected one of prompt, stdin, fd:N, file:PATH, env:VAR, cmd:COMMAND",
+                s
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PassphraseSource;
+    use std::path::PathBuf;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(
+            "prompt".parse::<PassphraseSource>().unwrap(),
+            PassphraseSource::Prompt
+        );
+        assert_eq!(
+            "fd:3".parse::<PassphraseSource>().unwrap(),
+            PassphraseSource::Fd(3)
+        );
+        assert_eq!(
+            "file:/a:b".parse::<PassphraseSource>().unwrap(),
+            PassphraseSource::File(PathBuf::from("/a:b"))
+        );
+        assert_eq!(
+            "cmd:pass show gpgsync".parse::<PassphraseSource>().unwrap(),
+            PassphraseSource::Command("pass show gpgsync".to_string())
+        );
+        assert!("secret".parse::<PassphraseSource>().is_err());
+        assert!("fd:x".parse::<PassphraseSource>().is_err());
+    }
+
+    #[test]
+    fn test_read() {
+        std::env::set_var("GPGSYNC_TEST_PASSPHRASE", "from env");
+        assert_eq!(
+            PassphraseSource::Env("GPGSYNC_TEST_PASSPHRASE".to_string())
+                .read("")
+                .unwrap()
+                .expose_str(),
+            "from env"
+        );
+        assert_eq!(
+            PassphraseSource::Command("printf 'from cmd\\nsecond line'".to_string())
+                .read("")
+                .unwrap()
+                .expose_str(),
+            "from cmd"
+        );
+        assert!(PassphraseSource::Command("false".to_string())
+            .read("")
+            .is_err());
+    }
+}
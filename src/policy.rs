@@ -0,0 +1,274 @@
+use std::io;
+use std::iter;
+use std::path::Path;
+
+use anyhow::{anyhow, Context};
+use globset::{GlobBuilder, GlobMatcher};
+use serde::Deserialize;
+
+use crate::passphrase::PassphraseSource;
+use crate::secret::Secret;
+
+/// Determines how files are encrypted and how they can be decrypted again.
+#[derive(Clone)]
+pub enum EncryptionPolicy {
+    /// Symmetric encryption, all files share a single passphrase.
+    Passphrase(Secret),
+    /// Public-key encryption to one or more recipients from the local keyring,
+    /// given by their fingerprints.  Decryption uses the secret keys of the
+    /// keyring, which are unlocked by the gpg-agent.
+    Recipients(Vec<String>),
+}
+
+/// File name of the policy file.  Will be read from the plain root directory.
+pub const POLICY_FILENAME: &str = ".gpgsync.toml";
+
+/// A rule of the policy file, e. g.
+///
+/// ```toml
+/// [[rule]]
+/// path = "work"
+/// recipients = ["0123456789ABCDEF0123456789ABCDEF01234567"]
+///
+/// [[rule]]
+/// path = "diary"
+/// passphrase = "cmd:pass show diary"
+/// ```
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RuleConfig {
+    /// Glob of the paths, relative to the plain root, that the rule applies to.
+    path: String,
+    /// Where to read the passphrase from, like `--passphrase`, so that the
+    /// passphrase itself is not stored in the file.
+    passphrase: Option<String>,
+    #[serde(default)]
+    recipients: Vec<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    #[serde(default, rename = "rule")]
+    rules: Vec<RuleConfig>,
+}
+
+/// The encryption policies for all files.
+///
+/// Files use the default policy unless a rule of the policy file applies to
+/// them.  A rule applies to a path if its glob matches the path or one of its
+/// parent directories, and the first rule that applies wins.
+pub struct PolicySet {
+    default: EncryptionPolicy,
+    rules: Vec<(GlobMatcher, EncryptionPolicy)>,
+}
+
+impl PolicySet {
+    /// Returns a policy set that uses `default` for all files.
+    pub fn new(default: EncryptionPolicy) -> Self {
+        Self {
+            default,
+            rules: Vec::new(),
+        }
+    }
+
+    /// Reads the rules from the policy file at `fp`, if there is one, and the
+    /// passphrases of its rules.
+    pub fn load(fp: &Path, default: EncryptionPolicy) -> anyhow::Result<Self> {
+        let s = match std::fs::read_to_string(fp) {
+            Ok(s) => s,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Self::new(default)),
+            Err(e) => return Err(e.into()),
+        };
+        let policy_file: PolicyFile =
+            toml::from_str(&s).with_context(|| format!("invalid policy file {:?}", fp))?;
+
+        let mut rules = Vec::new();
+        for rule in policy_file.rules {
+            let matcher = GlobBuilder::new(&rule.path)
+                .literal_separator(true)
+                .build()?
+                .compile_matcher();
+            let policy = match (&rule.passphrase, rule.recipients.is_empty()) {
+                (Some(source), true) => {
+                    let source = source.parse::<PassphraseSource>().with_context(|| {
+                        format!("invalid passphrase source in the rule for {:?}", rule.path)
+                    })?;
+                    let passphrase = source
+                        .read(&format!("Passphrase for {}: ", rule.path))
+                        .with_context(|| {
+                            format!("could not read the passphrase for {:?}", rule.path)
+                        })?;
+                    EncryptionPolicy::Passphrase(passphrase)
+                }
+                (None, false) => EncryptionPolicy::Recipients(rule.recipients),
+                _ => {
+                    return Err(anyhow!(
+                        "rule for {:?} needs either a passphrase or recipients",
+                        rule.path
+                    ))
+                }
+            };
+            rules.push((matcher, policy));
+        }
+
+        Ok(Self { default, rules })
+    }
+
+    /// Returns the policy of the first rule that applies to `rel_path`.
+    pub fn rule_for(&self, rel_path: &Path) -> Option<&EncryptionPolicy> {
+        self.rules
+            .iter()
+            .find(|(matcher, _)| rel_path.ancestors().any(|p| matcher.is_match(p)))
+            .map(|(_, policy)| policy)
+    }
+
+    /// Returns the policy for the file at `rel_path`, relative to the roots.
+    pub fn policy_for(&self, rel_path: &Path) -> &EncryptionPolicy {
+        self.rule_for(rel_path).unwrap_or(&self.default)
+    }
+
+    /// Returns the same rules with another default policy.
+    pub fn with_default(&self, default: EncryptionPolicy) -> Self {
+        Self {
+            default,
+            rules: self.rules.clone(),
+        }
+    }
+
+    /// Returns the policy for the name key, which everybody who can decrypt
+    /// one of the files has to be able to decrypt: the default policy if the
+    /// rules don't differ from it, all recipients of all policies otherwise.
+    /// Passphrases can't be combined with each other or with recipients, so
+    /// rules with other passphrases are an error.
+    pub fn name_key_policy(&self) -> anyhow::Result<EncryptionPolicy> {
+        let policies = || iter::once(&self.default).chain(self.rules.iter().map(|(_, p)| p));
+        if policies().all(|p| same_policy(p, &self.default)) {
+            return Ok(self.default.clone());
+        }
+
+        let mut all_recipients = Vec::new();
+        for policy in policies() {
+            match policy {
+                EncryptionPolicy::Recipients(recipients) => {
+                    for r in recipients {
+                        if !all_recipients.contains(r) {
+                            all_recipients.push(r.clone());
+                        }
+                    }
+                }
+                EncryptionPolicy::Passphrase(_) => {
+                    return Err(anyhow!(
+                        "names can't be encrypted if the rules of the policy file use other \
+                         passphrases or recipients than the default, unless all of them use \
+                         recipients: the name key can't be encrypted for all of them"
+                    ))
+                }
+            }
+        }
+        Ok(EncryptionPolicy::Recipients(all_recipients))
+    }
+}
+
+/// Returns whether files encrypted with `a` and `b` can be decrypted by the
+/// same people.
+fn same_policy(a: &EncryptionPolicy, b: &EncryptionPolicy) -> bool {
+    match (a, b) {
+        (EncryptionPolicy::Passphrase(a), EncryptionPolicy::Passphrase(b)) => {
+            a.expose() == b.expose()
+        }
+        (EncryptionPolicy::Recipients(a), EncryptionPolicy::Recipients(b)) => {
+            a.iter().all(|r| b.contains(r)) && b.iter().all(|r| a.contains(r))
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{EncryptionPolicy, PolicySet};
+    use std::path::Path;
+
+    #[test]
+    fn test_policy_for() {
+        let dir = tempfile::tempdir().unwrap();
+        let fp = dir.path().join("gpgsync.toml");
+        std::env::set_var("GPGSYNC_TEST_DIARY_PASSPHRASE", "diary");
+        std::fs::write(
+            &fp,
+            r#"
+            [[rule]]
+            path = "work"
+            recipients = ["AAAA", "BBBB"]
+
+            [[rule]]
+            path = "**/*.diary"
+            passphrase = "env:GPGSYNC_TEST_DIARY_PASSPHRASE"
+            "#,
+        )
+        .unwrap();
+
+        let policies = PolicySet::load(
+            &fp,
+            EncryptionPolicy::Passphrase("default".to_string().into()),
+        )
+        .unwrap();
+
+        let passphrase = |p: &str| match policies.policy_for(Path::new(p)) {
+            EncryptionPolicy::Passphrase(passphrase) => Some(passphrase.expose_str().to_string()),
+            EncryptionPolicy::Recipients(_) => None,
+        };
+        assert_eq!(passphrase("work/notes.txt"), None);
+        assert_eq!(passphrase("work/sub/notes.diary"), None);
+        assert_eq!(passphrase("2020/notes.diary"), Some("diary".to_string()));
+        assert_eq!(passphrase("workshop.txt"), Some("default".to_string()));
+        assert_eq!(passphrase("notes.txt"), Some("default".to_string()));
+
+        // passphrases themselves are not accepted
+        std::fs::write(&fp, "[[rule]]\npath = \"diary\"\npassphrase = \"diary\"\n").unwrap();
+        assert!(PolicySet::load(
+            &fp,
+            EncryptionPolicy::Passphrase("default".to_string().into())
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_name_key_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let fp = dir.path().join("gpgsync.toml");
+        let recipients =
+            |rs: &[&str]| EncryptionPolicy::Recipients(rs.iter().map(|r| r.to_string()).collect());
+        let name_key_recipients = |policies: &PolicySet| match policies.name_key_policy() {
+            Ok(EncryptionPolicy::Recipients(rs)) => Some(rs),
+            _ => None,
+        };
+
+        // without rules, the default is used
+        let passphrase = EncryptionPolicy::Passphrase("default".to_string().into());
+        let policies = PolicySet::load(&fp, passphrase.clone()).unwrap();
+        assert!(matches!(
+            policies.name_key_policy(),
+            Ok(EncryptionPolicy::Passphrase(p)) if p.expose_str() == "default"
+        ));
+
+        // the key is encrypted to the recipients of all rules
+        std::fs::write(
+            &fp,
+            "[[rule]]\npath = \"work\"\nrecipients = [\"BBBB\", \"CCCC\"]\n",
+        )
+        .unwrap();
+        let policies = PolicySet::load(&fp, recipients(&["AAAA", "BBBB"])).unwrap();
+        assert_eq!(
+            name_key_recipients(&policies),
+            Some(vec![
+                "AAAA".to_string(),
+                "BBBB".to_string(),
+                "CCCC".to_string()
+            ])
+        );
+
+        // but not for a passphrase as well
+        assert!(policies.with_default(passphrase).name_key_policy().is_err());
+    }
+}
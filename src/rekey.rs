@@ -0,0 +1,122 @@
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+
+use crate::backend::CryptoBackend;
+use crate::filesync::FileStatus;
+use crate::policy::{EncryptionPolicy, PolicySet, POLICY_FILENAME};
+use crate::secret::SecretBuf;
+use crate::syncentity::{SyncEntity, SyncRoots};
+use crate::{
+    fileutils, hidden, is_ciphertext_file, load_db, load_name_cipher, name_cipher, validate_args,
+    DB_FILENAME,
+};
+
+/// Re-encrypts every file in the gpg root that uses the default policy
+/// according to `new_policy`.
+///
+/// Files that a rule of the policy file applies to are left alone, their
+/// passphrase or recipients are not affected by a change of the default.  The
+/// name key, if the names are encrypted, is re-encrypted for the new default
+/// and the rules, see `PolicySet::name_key_policy()`.
+/// Each file is decrypted using `old_policy` and atomically replaced by its new
+/// ciphertext.  The progress is recorded in the database, so an interrupted
+/// rekey is resumed by running it again with the same arguments.  Files that
+/// were in sync before stay in sync in the database, so that the next sync
+/// doesn't see them as modified.  No sync may run on the same directories at
+/// the same time.
+pub fn rekey(
+    plain_root: &Path,
+    gpg_root: &Path,
+    backend: &dyn CryptoBackend,
+    old_policy: &EncryptionPolicy,
+    new_policy: &EncryptionPolicy,
+) -> anyhow::Result<()> {
+    let plain_root = std::fs::canonicalize(plain_root)?;
+    let gpg_root = std::fs::canonicalize(gpg_root)?;
+
+    validate_args(&plain_root, &gpg_root)?;
+
+    let db_path = plain_root.join(DB_FILENAME);
+    let mut db = load_db(&db_path, &gpg_root, backend.extension())?;
+    let policies = PolicySet::load(&plain_root.join(POLICY_FILENAME), old_policy.clone())?;
+    let new_policies = policies.with_default(new_policy.clone());
+    // an interrupted rekey may have re-encrypted the name key already
+    let name_cipher = match load_name_cipher(&mut db, &gpg_root, backend, &policies, false) {
+        Ok(name_cipher) => name_cipher,
+        Err(_) => load_name_cipher(&mut db, &gpg_root, backend, &new_policies, false)?,
+    };
+
+    let roots = Arc::new(SyncRoots {
+        plain_root,
+        gpg_root,
+        extension: backend.extension(),
+        name_cipher,
+    });
+
+    let mut ses = Vec::new();
+    fileutils::visit_dir(&roots.gpg_root, &mut |de| {
+        if let Some(se) = SyncEntity::from_gpg(&de.path(), &roots) {
+            if is_ciphertext_file(backend, &de.path()) && !hidden::is_denied(se.rel_without_gpg()) {
+                ses.push(se);
+            }
+        }
+    })?;
+
+    db.begin_rekey();
+    db.save_db(&db_path)?;
+
+    for se in ses {
+        let p = se.as_gpg();
+        if db.is_rekeyed(&se) {
+            continue;
+        }
+        if policies.rule_for(se.rel_without_gpg()).is_some() {
+            println!("not rekeying {:?}, a rule of the policy file applies", &p);
+            continue;
+        }
+        println!("rekeying {:?}", &p);
+
+        let (plain_status, gpg_status_db) = db.get_file_status(&se);
+        let gpg_status_before = fileutils::file_status(&p)?;
+
+        let mut plaintext = SecretBuf::new();
+        match backend.decrypt(&mut fileutils::open_read(&p)?, &mut plaintext, old_policy) {
+            Ok(()) => {
+                fileutils::write_atomically(&p, |f| {
+                    backend.encrypt(&mut plaintext.as_slice(), f, new_policy)
+                })?;
+            }
+            Err(e) => {
+                // an interrupted run may have replaced the file without recording it
+                let mut f = fileutils::open_read(&p)?;
+                if backend
+                    .decrypt(&mut f, &mut io::sink(), new_policy)
+                    .is_err()
+                {
+                    return Err(anyhow!("could not decrypt {:?}: {}", &p, e));
+                }
+            }
+        }
+
+        // only a file that was in sync before is known to be in sync now
+        if gpg_status_db.same_metadata(&gpg_status_before) {
+            // the plaintext and thus its hash stay the same
+            let hash = gpg_status_db.hash().cloned();
+            let gpg_status =
+                fileutils::file_status(&p)?.with_hash(&FileStatus::Nonexistent, || Ok(hash))?;
+            db.set_file_status(&se, plain_status, gpg_status);
+        }
+        db.set_rekeyed(&se);
+        db.save_db(&db_path)?;
+    }
+
+    name_cipher::rekey_name_key(&roots.gpg_root, backend, &policies, &new_policies)?;
+
+    db.finish_rekey();
+    db.save_db(&db_path)?;
+
+    Ok(())
+}
@@ -0,0 +1,321 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::backend::CryptoBackend;
+use crate::filesync::{FileMeta, FileStatus, SyncAction};
+use crate::policy::PolicySet;
+use crate::syncdb::SyncDb;
+use crate::syncentity::{SyncEntity, SyncRoots};
+use crate::{fileutils, gpg_file_hash, merge, plain_file_hash};
+
+/// Entities with the sync actions to be performed on them.
+type Work = Vec<(SyncEntity, SyncAction)>;
+
+/// The side of the sync a file was changed on.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Side {
+    Plain,
+    Gpg,
+}
+
+/// A file that was renamed on one side, whose counterpart on the other side
+/// is to be renamed the same way.
+#[derive(Debug)]
+pub struct Rename {
+    pub from: SyncEntity,
+    pub to: SyncEntity,
+    pub side: Side,
+}
+
+/// A directory that was renamed on one side, whose counterpart on the other
+/// side is to be renamed the same way.  The paths are relative to the roots.
+#[derive(Debug)]
+pub struct DirRename {
+    pub from: PathBuf,
+    pub to: PathBuf,
+    pub side: Side,
+}
+
+/// Finds the files renamed while the sync wasn't running.
+///
+/// A rename shows up in `work` as a file deleted on one side and a new file
+/// on the same side.  Both are paired if the new file has the plaintext hash
+/// that was recorded for the deleted one, and if the same policy applies to
+/// both.  Returns the rest of `work` and the renames.
+pub fn detect_renames(
+    work: Work,
+    db: &SyncDb,
+    backend: &dyn CryptoBackend,
+    policies: &PolicySet,
+) -> io::Result<(Work, Vec<Rename>)> {
+    let mut rest = Vec::new();
+    let mut deleted = Vec::new();
+    let mut added = Vec::new();
+    for (se, sync_action) in work {
+        match (sync_action, db.get_file_status(&se)) {
+            (SyncAction::DeleteGpg, (FileStatus::Existent(meta), _)) if meta.hash.is_some() => {
+                deleted.push((se, sync_action, Side::Plain, meta))
+            }
+            (SyncAction::DeletePlain, (_, FileStatus::Existent(meta))) if meta.hash.is_some() => {
+                deleted.push((se, sync_action, Side::Gpg, meta))
+            }
+            (SyncAction::PushPlain, (FileStatus::Nonexistent, FileStatus::Nonexistent)) => {
+                added.push((se, sync_action, Side::Plain))
+            }
+            (SyncAction::PushGpg, (FileStatus::Nonexistent, FileStatus::Nonexistent)) => {
+                added.push((se, sync_action, Side::Gpg))
+            }
+            _ => rest.push((se, sync_action)),
+        }
+    }
+
+    let mut renames = Vec::new();
+    for (to, sync_action, side) in added {
+        match find_renamed(&to, side, &deleted, backend, policies)? {
+            Some(i) => {
+                let (from, _, _, _) = deleted.remove(i);
+                renames.push(Rename { from, to, side });
+            }
+            None => rest.push((to, sync_action)),
+        }
+    }
+    rest.extend(
+        deleted
+            .into_iter()
+            .map(|(se, sync_action, _, _)| (se, sync_action)),
+    );
+
+    Ok((rest, renames))
+}
+
+/// Returns the index of the entry of `deleted` that the new file `to` was
+/// renamed from.  The new file is only hashed if a deleted one has its size.
+fn find_renamed(
+    to: &SyncEntity,
+    side: Side,
+    deleted: &[(SyncEntity, SyncAction, Side, FileMeta)],
+    backend: &dyn CryptoBackend,
+    policies: &PolicySet,
+) -> io::Result<Option<usize>> {
+    let p = match side {
+        Side::Plain => to.as_plain(),
+        Side::Gpg => to.as_gpg(),
+    };
+    let size = match fileutils::file_status(&p)? {
+        FileStatus::Existent(meta) => meta.size,
+        FileStatus::Nonexistent => return Ok(None),
+    };
+
+    let policy = to.policy(policies);
+    let candidates = deleted
+        .iter()
+        .enumerate()
+        .filter(|(_, (from, _, s, meta))| {
+            *s == side && meta.size == size && std::ptr::eq(from.policy(policies), policy)
+        })
+        .collect::<Vec<_>>();
+    if candidates.is_empty() {
+        return Ok(None);
+    }
+
+    let hash = match side {
+        Side::Plain => plain_file_hash(&p)?,
+        Side::Gpg => gpg_file_hash(&p, backend, policy)?,
+    };
+    Ok(candidates
+        .into_iter()
+        .find(|(_, (_, _, _, meta))| meta.hash.as_ref() == Some(&hash))
+        .map(|(i, _)| i))
+}
+
+/// Returns the rename of the counterpart of a file that was seen being
+/// renamed from `from` to `to` on `side`, if the counterpart can simply be
+/// renamed along.
+///
+/// That is the case if neither entity is conflicted, the counterpart is
+/// unchanged since the last sync, nothing is in the way at its new name, and
+/// the same policy applies to both.  Whether the renamed file itself was also
+/// modified doesn't matter, that is synced after the rename.
+pub fn watched_rename(
+    from: SyncEntity,
+    to: SyncEntity,
+    side: Side,
+    db: &SyncDb,
+    policies: &PolicySet,
+) -> io::Result<Option<Rename>> {
+    if db.conflict(&from).is_some()
+        || db.conflict(&to).is_some()
+        || !std::ptr::eq(from.policy(policies), to.policy(policies))
+    {
+        return Ok(None);
+    }
+
+    let (renamed, counterpart, counterpart_to) = match side {
+        Side::Plain => (to.as_plain(), from.as_gpg(), to.as_gpg()),
+        Side::Gpg => (to.as_gpg(), from.as_plain(), to.as_plain()),
+    };
+    let (plain_status, gpg_status) = db.get_file_status(&from);
+    let counterpart_status = match side {
+        Side::Plain => gpg_status,
+        Side::Gpg => plain_status,
+    };
+    let counterpart_unchanged = counterpart_status != FileStatus::Nonexistent
+        && counterpart_status.same_metadata(&fileutils::file_status(&counterpart)?);
+    let nothing_in_way = !counterpart_to.exists()
+        && db.get_file_status(&to) == (FileStatus::Nonexistent, FileStatus::Nonexistent);
+
+    Ok(
+        if renamed.is_file() && counterpart_unchanged && nothing_in_way {
+            Some(Rename { from, to, side })
+        } else {
+            None
+        },
+    )
+}
+
+/// Returns the rename of the counterpart of a directory that was seen being
+/// renamed from `from` to `to` on `side`, if the counterpart can simply be
+/// renamed along.
+///
+/// That is the case if nothing is in the way at its new name, and all files
+/// in it are unchanged since the last sync and keep their policy.
+pub fn watched_dir_rename(
+    from: PathBuf,
+    to: PathBuf,
+    side: Side,
+    roots: &Arc<SyncRoots>,
+    db: &SyncDb,
+    policies: &PolicySet,
+) -> io::Result<Option<DirRename>> {
+    if !counterpart_dir(&from, side, roots).is_dir() || counterpart_dir(&to, side, roots).exists() {
+        return Ok(None);
+    }
+
+    for rel in db.entities_under(&from) {
+        let from_se = SyncEntity::from_rel(&rel, roots);
+        let to_se = SyncEntity::from_rel(&to.join(rel.strip_prefix(&from).unwrap()), roots);
+        let (plain_status, gpg_status) = db.get_file_status(&from_se);
+        let (counterpart, counterpart_status) = match side {
+            Side::Plain => (from_se.as_gpg(), gpg_status),
+            Side::Gpg => (from_se.as_plain(), plain_status),
+        };
+        if !std::ptr::eq(from_se.policy(policies), to_se.policy(policies))
+            || !counterpart_status.same_metadata(&fileutils::file_status(&counterpart)?)
+        {
+            return Ok(None);
+        }
+    }
+
+    Ok(Some(DirRename { from, to, side }))
+}
+
+/// Returns the counterpart on the other side of the directory `rel` on `side`.
+fn counterpart_dir(rel: &Path, side: Side, roots: &SyncRoots) -> PathBuf {
+    match side {
+        Side::Plain => roots.gpg_path(rel, false),
+        Side::Gpg => roots.plain_root.join(rel),
+    }
+}
+
+/// Renames the counterpart of the directory renamed on `rename.side`, and
+/// moves the base versions and database entries of all files in it along.
+pub fn perform_dir_rename(
+    rename: &DirRename,
+    roots: &SyncRoots,
+    db: &mut SyncDb,
+) -> io::Result<()> {
+    let (from, to) = (
+        counterpart_dir(&rename.from, rename.side, roots),
+        counterpart_dir(&rename.to, rename.side, roots),
+    );
+    println!("renaming {:?} to {:?}", from, to);
+
+    if rename.side == Side::Plain {
+        roots.store_long_names(&rename.to, false)?;
+    }
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::rename(&from, &to)?;
+    merge::rename_base_dir(roots, &rename.from, &rename.to)?;
+    db.rename_dir(&rename.from, &rename.to);
+
+    Ok(())
+}
+
+/// Renames the counterpart of the file renamed on `rename.side`, and moves
+/// its base version and database entry along.
+///
+/// Renaming keeps the size, mtime and inode, so the recorded statuses stay
+/// valid.  If this is interrupted before the database is saved, the next sync
+/// finds the old entity gone on both sides and the new one added on both
+/// sides with the same contents, which needs no further action either.
+pub fn perform_rename(rename: &Rename, db: &mut SyncDb) -> io::Result<()> {
+    let (from, to) = match rename.side {
+        Side::Plain => (rename.from.as_gpg(), rename.to.as_gpg_to_create()?),
+        Side::Gpg => (rename.from.as_plain(), rename.to.as_plain()),
+    };
+    println!("renaming {:?} to {:?}", from, to);
+
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::rename(&from, &to)?;
+    merge::rename_base(&rename.from, &rename.to)?;
+    db.rename(&rename.from, &rename.to);
+
+    Ok(())
+}
+
+#[cfg(all(test, feature = "age"))]
+mod test {
+    use super::{detect_renames, Side};
+    use crate::filesync::{FileStatus, SyncAction};
+    use crate::policy::{EncryptionPolicy, PolicySet};
+    use crate::syncdb::SyncDb;
+    use crate::syncentity::{SyncEntity, SyncRoots};
+    use crate::{fileutils, plain_file_hash, AgeBackend};
+    use std::path::Path;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_detect_renames() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let roots = Arc::new(SyncRoots {
+            plain_root: dir.join("plain"),
+            gpg_root: dir.join("gpg"),
+            extension: "age",
+            name_cipher: None,
+        });
+        std::fs::create_dir_all(&roots.plain_root).unwrap();
+        let se = |rel: &str| SyncEntity::from_rel(Path::new(rel), &roots);
+
+        // "old" was synced with the contents that "new" has now
+        let mut db = SyncDb::new(&roots.gpg_root);
+        std::fs::write(se("new").as_plain(), b"contents").unwrap();
+        let status = fileutils::file_status(&se("new").as_plain())
+            .unwrap()
+            .with_hash(&FileStatus::Nonexistent, || {
+                plain_file_hash(&se("new").as_plain()).map(Some)
+            })
+            .unwrap();
+        db.set_file_status(&se("old"), status.clone(), status);
+        std::fs::write(se("other").as_plain(), b"contents!").unwrap();
+
+        let work = vec![
+            (se("old"), SyncAction::DeleteGpg),
+            (se("other"), SyncAction::PushPlain),
+            (se("new"), SyncAction::PushPlain),
+        ];
+        let policies = PolicySet::new(EncryptionPolicy::Passphrase("test".to_string().into()));
+        let (rest, renames) = detect_renames(work, &db, &AgeBackend::new(), &policies).unwrap();
+
+        assert_eq!(rest, vec![(se("other"), SyncAction::PushPlain)]);
+        assert_eq!(renames.len(), 1);
+        assert_eq!(renames[0].from, se("old"));
+        assert_eq!(renames[0].to, se("new"));
+        assert_eq!(renames[0].side, Side::Plain);
+    }
+}
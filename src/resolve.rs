@@ -0,0 +1,282 @@
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use similar::{ChangeTag, TextDiff};
+
+use crate::backend::CryptoBackend;
+use crate::filesync::SyncAction;
+use crate::policy::{EncryptionPolicy, PolicySet, POLICY_FILENAME};
+use crate::secret::SecretBuf;
+use crate::syncentity::{SyncEntity, SyncRoots};
+use crate::{
+    fileutils, load_db, load_name_cipher, perform_sync_action_and_update_db, validate_args,
+    DB_FILENAME,
+};
+
+/// Lets the user resolve each conflict recorded in the database.
+///
+/// For every conflicted file, the two versions are compared and the user is
+/// asked which one to keep, whether to keep both, or to merge them in an
+/// editor.  The result is synced and the conflict copy removed.  No sync may
+/// run on the same directories at the same time.
+pub fn resolve(
+    plain_root: &Path,
+    gpg_root: &Path,
+    backend: &dyn CryptoBackend,
+    policy: EncryptionPolicy,
+) -> anyhow::Result<()> {
+    let plain_root = std::fs::canonicalize(plain_root)?;
+    let gpg_root = std::fs::canonicalize(gpg_root)?;
+
+    validate_args(&plain_root, &gpg_root)?;
+
+    let db_path = plain_root.join(DB_FILENAME);
+    let mut db = load_db(&db_path, &gpg_root, backend.extension())?;
+    let policies = PolicySet::load(&plain_root.join(POLICY_FILENAME), policy)?;
+    let name_cipher = load_name_cipher(&mut db, &gpg_root, backend, &policies, false)?;
+
+    let roots = Arc::new(SyncRoots {
+        plain_root,
+        gpg_root,
+        extension: backend.extension(),
+        name_cipher,
+    });
+
+    let mut conflicted: Vec<_> = db.conflicted().cloned().collect();
+    conflicted.sort();
+    if conflicted.is_empty() {
+        println!("There are no conflicts.");
+    }
+
+    for rel in conflicted {
+        let se = SyncEntity::from_rel(&rel, &roots);
+        let copy = SyncEntity::from_rel(&db.conflict(&se).unwrap().copy, &roots);
+        let policy = se.policy(&policies);
+
+        let plain = match se.as_plain().exists() {
+            true => Some(std::fs::read(se.as_plain())?),
+            false => None,
+        };
+        let gpg = match se.as_gpg().exists() {
+            true => {
+                let mut decrypted = SecretBuf::new();
+                backend.decrypt(
+                    &mut fileutils::open_read(&se.as_gpg())?,
+                    &mut decrypted,
+                    policy,
+                )?;
+                Some(decrypted)
+            }
+            false => None,
+        };
+        let plain_text = plain.as_deref().unwrap_or_default();
+        let gpg_text = gpg.as_ref().map(|g| g.as_slice()).unwrap_or_default();
+
+        println!();
+        println!("Conflict in {:?}", &rel);
+        describe("plain", &se.as_plain())?;
+        describe("gpg", &se.as_gpg())?;
+        print_diff(plain_text, gpg_text);
+
+        let mut keep_copy = false;
+        let sync_action = loop {
+            match prompt("Keep [p]lain, [g]pg, [b]oth, [e]dit a merge, or [s]kip? ")?.as_str() {
+                "p" => break keep_plain(&se),
+                "g" => match gpg {
+                    Some(_) => break SyncAction::PushGpg,
+                    None => break SyncAction::DeletePlain,
+                },
+                "b" => {
+                    // the gpg version stays in the conflict copy, which becomes a regular file
+                    if let Some(gpg) = &gpg {
+                        fileutils::write_atomically(&copy.as_plain(), |f| {
+                            f.write_all(gpg.as_slice())
+                        })?;
+                        perform_sync_action_and_update_db(
+                            SyncAction::PushPlain,
+                            &copy,
+                            &mut db,
+                            backend,
+                            copy.policy(&policies),
+                        )?;
+                        keep_copy = true;
+                    }
+                    break keep_plain(&se);
+                }
+                "e" => {
+                    let (plain_text, gpg_text) = match (
+                        std::str::from_utf8(plain_text),
+                        std::str::from_utf8(gpg_text),
+                    ) {
+                        (Ok(p), Ok(g)) => (p, g),
+                        _ => {
+                            println!("Binary files can't be merged.");
+                            continue;
+                        }
+                    };
+                    edit_merge(&se.as_plain(), &merge_with_markers(plain_text, gpg_text))?;
+                    break SyncAction::PushPlain;
+                }
+                "s" => break SyncAction::None,
+                _ => {}
+            }
+        };
+        if sync_action == SyncAction::None {
+            continue;
+        }
+
+        db.clear_conflict(&se);
+        if !keep_copy && copy.as_plain().exists() {
+            std::fs::remove_file(copy.as_plain())?;
+        }
+        perform_sync_action_and_update_db(sync_action, &se, &mut db, backend, policy)?;
+        db.save_db(&db_path)?;
+        println!("Resolved {:?}", &rel);
+    }
+
+    Ok(())
+}
+
+/// The action that syncs the plain version, which may have been deleted.
+fn keep_plain(se: &SyncEntity) -> SyncAction {
+    match se.as_plain().exists() {
+        true => SyncAction::PushPlain,
+        false => SyncAction::DeleteGpg,
+    }
+}
+
+fn describe(name: &str, p: &Path) -> io::Result<()> {
+    match std::fs::metadata(p) {
+        Ok(metadata) => {
+            let mtime: chrono::DateTime<chrono::Local> = metadata.modified()?.into();
+            println!(
+                "  {}: {} bytes, modified {}",
+                name,
+                metadata.len(),
+                mtime.format("%Y-%m-%d %H:%M:%S")
+            );
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => println!("  {}: deleted", name),
+        Err(e) => return Err(e),
+    }
+    Ok(())
+}
+
+fn print_diff(plain: &[u8], gpg: &[u8]) {
+    match (std::str::from_utf8(plain), std::str::from_utf8(gpg)) {
+        (Ok(plain), Ok(gpg)) => print!(
+            "{}",
+            TextDiff::from_lines(plain, gpg)
+                .unified_diff()
+                .header("plain", "gpg")
+        ),
+        _ => println!("  binary files differ"),
+    }
+}
+
+fn prompt(question: &str) -> io::Result<String> {
+    print!("{}", question);
+    io::stdout().flush()?;
+
+    let mut answer = String::new();
+    if io::stdin().read_line(&mut answer)? == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "no answer on stdin",
+        ));
+    }
+    Ok(answer.trim().to_lowercase())
+}
+
+const MARKER_PLAIN: &str = "<<<<<<< plain\n";
+const MARKER_SEPARATOR: &str = "=======\n";
+const MARKER_GPG: &str = ">>>>>>> gpg\n";
+
+/// Returns the lines that both versions have in common, and the differing
+/// parts of both between conflict markers like those of git.
+fn merge_with_markers(plain: &str, gpg: &str) -> String {
+    fn push_lines(merged: &mut String, lines: &mut String) {
+        merged.push_str(lines);
+        if !lines.is_empty() && !lines.ends_with('\n') {
+            merged.push('\n');
+        }
+        lines.clear();
+    }
+
+    fn push_hunk(merged: &mut String, plain_lines: &mut String, gpg_lines: &mut String) {
+        if plain_lines.is_empty() && gpg_lines.is_empty() {
+            return;
+        }
+        merged.push_str(MARKER_PLAIN);
+        push_lines(merged, plain_lines);
+        merged.push_str(MARKER_SEPARATOR);
+        push_lines(merged, gpg_lines);
+        merged.push_str(MARKER_GPG);
+    }
+
+    let mut merged = String::new();
+    let (mut plain_lines, mut gpg_lines) = (String::new(), String::new());
+    for change in TextDiff::from_lines(plain, gpg).iter_all_changes() {
+        match change.tag() {
+            ChangeTag::Equal => {
+                push_hunk(&mut merged, &mut plain_lines, &mut gpg_lines);
+                merged.push_str(change.value());
+            }
+            ChangeTag::Delete => plain_lines.push_str(change.value()),
+            ChangeTag::Insert => gpg_lines.push_str(change.value()),
+        }
+    }
+    push_hunk(&mut merged, &mut plain_lines, &mut gpg_lines);
+
+    merged
+}
+
+/// Lets the user edit `merged` in `$EDITOR` and replaces `plain_path` with the
+/// result.
+fn edit_merge(plain_path: &Path, merged: &str) -> anyhow::Result<()> {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(plain_path.file_name().unwrap());
+    name.push(".gpgsync-merge");
+    let merge_path = plain_path.with_file_name(name);
+    std::fs::write(&merge_path, merged)?;
+
+    let editor = std::env::var("EDITOR").unwrap_or_else(|_| "vi".to_string());
+    loop {
+        let status = std::process::Command::new("sh")
+            .arg("-c")
+            .arg(format!("{} \"$1\"", editor))
+            .arg("sh")
+            .arg(&merge_path)
+            .status()?;
+        if !status.success() {
+            std::fs::remove_file(&merge_path)?;
+            return Err(anyhow!("editor {:?} failed: {}", editor, status));
+        }
+
+        let merged = std::fs::read_to_string(&merge_path)?;
+        if !merged.contains(MARKER_PLAIN)
+            || prompt("The merge still contains conflict markers.  Use it anyway? [y/N] ")? == "y"
+        {
+            break;
+        }
+    }
+
+    std::fs::rename(&merge_path, plain_path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::merge_with_markers;
+
+    #[test]
+    fn test_merge_with_markers() {
+        assert_eq!(
+            merge_with_markers("a\nb\nc\n", "a\nB\nc\nd"),
+            "a\n<<<<<<< plain\nb\n=======\nB\n>>>>>>> gpg\nc\n<<<<<<< plain\n=======\nd\n>>>>>>> gpg\n"
+        );
+        assert_eq!(merge_with_markers("same\n", "same\n"), "same\n");
+    }
+}
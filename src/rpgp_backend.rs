@@ -0,0 +1,201 @@
+use std::io::{self, Read, Write};
+
+use pgp::crypto::sym::SymmetricKeyAlgorithm;
+use pgp::packet::SymKeyEncryptedSessionKey;
+use pgp::ser::Serialize;
+use pgp::types::{SkeskVersion, StringToKey};
+use pgp::{Deserializable, Esk, Message, PlainSessionKey};
+use zeroize::Zeroizing;
+
+use crate::backend::{is_openpgp_message, CryptoBackend};
+use crate::policy::EncryptionPolicy;
+use crate::secret::SecretBuf;
+
+/// Encryption with rPGP, a pure-Rust OpenPGP implementation.
+///
+/// Produces the same symmetrically encrypted `.gpg` files as the gpgme
+/// backend, so both can be used on the same gpg root, but needs neither the
+/// gpgme library nor a GnuPG installation.  Only passphrases are supported,
+/// and each file is held in memory while it is en- or decrypted.
+///
+/// Our own copies of passphrases and plaintexts are wiped, but rPGP keeps
+/// copies of the plaintext in its packets and takes the passphrase as a
+/// `String` when encrypting, which it drops without wiping them.
+pub struct RpgpBackend;
+
+fn to_io_error(e: pgp::errors::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn passphrase(policy: &EncryptionPolicy) -> io::Result<&str> {
+    match policy {
+        EncryptionPolicy::Passphrase(passphrase) => Ok(passphrase.expose_str()),
+        EncryptionPolicy::Recipients(_) => Err(io::Error::other(
+            "the rpgp backend does not support encryption to recipients",
+        )),
+    }
+}
+
+/// Returns the session key of a message from its `skesk` packet, like rPGP's
+/// `decrypt_with_password`, but without copying the passphrase into a
+/// `String` that isn't wiped.
+fn session_key(
+    skesk: &SymKeyEncryptedSessionKey,
+    passphrase: &str,
+) -> pgp::errors::Result<PlainSessionKey> {
+    let sym_alg = skesk.sym_algorithm();
+    if sym_alg == SymmetricKeyAlgorithm::Plaintext {
+        return Err(pgp::errors::Error::Message(
+            "SKESK packet encryption algorithm cannot be plaintext".to_string(),
+        ));
+    }
+    // neither gpg nor this backend writes them, and rPGP's check of their
+    // S2K's hash algorithm is not public
+    if skesk.version() == SkeskVersion::V6 {
+        return Err(pgp::errors::Error::Message(
+            "v6 SKESK packets are not supported".to_string(),
+        ));
+    }
+    let key = Zeroizing::new(skesk.s2k().derive_key(passphrase, sym_alg.key_size())?);
+    match skesk.encrypted_key() {
+        Some(_) => skesk.decrypt(&key),
+        // without an encrypted session key, the derived key is the session key
+        None => Ok(PlainSessionKey::V3_4 {
+            sym_alg,
+            key: key.to_vec(),
+        }),
+    }
+}
+
+impl CryptoBackend for RpgpBackend {
+    fn encrypt(
+        &self,
+        plaintext_in: &mut (dyn Read + Send),
+        mut ciphertext_out: &mut (dyn Write + Send),
+        policy: &EncryptionPolicy,
+    ) -> io::Result<()> {
+        let passphrase = passphrase(policy)?;
+
+        let mut plaintext = SecretBuf::new();
+        io::copy(plaintext_in, &mut plaintext)?;
+
+        let mut rng = rand::thread_rng();
+        let s2k = StringToKey::new_default(&mut rng);
+        // the only way to pass rPGP the passphrase, it drops the copy itself
+        let msg = Message::new_literal_bytes("", plaintext.as_slice())
+            .encrypt_with_password_seipdv1(&mut rng, s2k, SymmetricKeyAlgorithm::AES256, || {
+                passphrase.to_string()
+            })
+            .map_err(to_io_error)?;
+
+        msg.to_writer(&mut ciphertext_out).map_err(to_io_error)?;
+
+        Ok(())
+    }
+
+    fn decrypt(
+        &self,
+        ciphertext_in: &mut (dyn Read + Send),
+        plaintext_out: &mut (dyn Write + Send),
+        policy: &EncryptionPolicy,
+    ) -> io::Result<()> {
+        let passphrase = passphrase(policy)?;
+
+        let mut ciphertext = Vec::new();
+        ciphertext_in.read_to_end(&mut ciphertext)?;
+
+        let msg = if ciphertext.starts_with(b"-----BEGIN") {
+            Message::from_armor_single(&ciphertext[..])
+                .map_err(to_io_error)?
+                .0
+        } else {
+            Message::from_bytes(&ciphertext[..]).map_err(to_io_error)?
+        };
+
+        let (esk, edata) = match &msg {
+            Message::Encrypted { esk, edata } => (esk, edata),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "not encrypted")),
+        };
+        let skesk = esk
+            .iter()
+            .find_map(|esk| match esk {
+                Esk::SymKeyEncryptedSessionKey(skesk) => Some(skesk),
+                _ => None,
+            })
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "not encrypted with a passphrase",
+                )
+            })?;
+        let session_key = session_key(skesk, passphrase).map_err(to_io_error)?;
+
+        // gpg compresses before encrypting, `get_content` takes care of that
+        let plaintext = Zeroizing::new(
+            edata
+                .decrypt(session_key)
+                .and_then(|decrypted| decrypted.get_content())
+                .map_err(to_io_error)?
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no literal data"))?,
+        );
+
+        plaintext_out.write_all(&plaintext)?;
+
+        Ok(())
+    }
+
+    fn extension(&self) -> &'static str {
+        "gpg"
+    }
+
+    fn is_ciphertext(&self, header: &[u8]) -> bool {
+        is_openpgp_message(header)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::RpgpBackend;
+    use crate::backend::CryptoBackend;
+    use crate::policy::EncryptionPolicy;
+
+    #[test]
+    fn test_decrypt_gnupg_file() {
+        let policy = EncryptionPolicy::Passphrase("test".to_string().into());
+        let mut plaintext = Vec::new();
+        RpgpBackend
+            .decrypt(
+                &mut &include_bytes!("notes.txt.gpg")[..],
+                &mut plaintext,
+                &policy,
+            )
+            .unwrap();
+        assert_eq!(plaintext, b"hello");
+
+        let wrong_policy = EncryptionPolicy::Passphrase("wrong".to_string().into());
+        assert!(RpgpBackend
+            .decrypt(
+                &mut &include_bytes!("notes.txt.gpg")[..],
+                &mut Vec::new(),
+                &wrong_policy,
+            )
+            .is_err());
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let policy = EncryptionPolicy::Passphrase("test".to_string().into());
+
+        let mut ciphertext = Vec::new();
+        RpgpBackend
+            .encrypt(&mut &b"hello"[..], &mut ciphertext, &policy)
+            .unwrap();
+        assert!(RpgpBackend.is_ciphertext(&ciphertext));
+
+        let mut plaintext = Vec::new();
+        RpgpBackend
+            .decrypt(&mut &ciphertext[..], &mut plaintext, &policy)
+            .unwrap();
+        assert_eq!(plaintext, b"hello");
+    }
+}
@@ -0,0 +1,239 @@
+use std::alloc::{self, Layout};
+use std::io::{self, Write};
+use std::ptr::NonNull;
+
+use zeroize::Zeroize;
+
+/// A secret like a passphrase.
+///
+/// The memory holding it is locked against being swapped out where possible,
+/// and it is wiped when the secret is dropped.  There is no `Debug`
+/// implementation, so that the secret can't end up in log output by accident.
+pub struct Secret {
+    bytes: LockedBytes,
+}
+
+impl Secret {
+    /// Moves `s` into locked memory and wipes it.
+    pub fn new(mut s: String) -> Self {
+        let mut bytes = LockedBytes::with_capacity(s.len());
+        bytes.extend_from_slice(s.as_bytes());
+        s.zeroize();
+        Self { bytes }
+    }
+
+    /// Copies `bytes` into a new secret, if they are valid UTF-8.
+    pub fn from_slice(bytes: &[u8]) -> io::Result<Self> {
+        std::str::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut locked = LockedBytes::with_capacity(bytes.len());
+        locked.extend_from_slice(bytes);
+        Ok(Self { bytes: locked })
+    }
+
+    pub fn expose(&self) -> &[u8] {
+        self.bytes.as_slice()
+    }
+
+    pub fn expose_str(&self) -> &str {
+        // constructed from valid UTF-8
+        std::str::from_utf8(self.bytes.as_slice()).unwrap()
+    }
+}
+
+impl From<String> for Secret {
+    fn from(s: String) -> Self {
+        Self::new(s)
+    }
+}
+
+impl Clone for Secret {
+    fn clone(&self) -> Self {
+        let mut bytes = LockedBytes::with_capacity(self.bytes.len);
+        bytes.extend_from_slice(self.bytes.as_slice());
+        Self { bytes }
+    }
+}
+
+/// A growable buffer for decrypted data that is wiped when dropped.
+///
+/// Unlike a plain `Vec`, it also wipes the old allocation whenever it grows,
+/// so that no copies of the contents are left behind on the heap.  Its memory
+/// is locked like that of a `Secret`, as far as the limit of locked memory
+/// allows, larger plaintexts may be swapped out.
+#[derive(Default)]
+pub struct SecretBuf {
+    bytes: LockedBytes,
+}
+
+impl SecretBuf {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        self.bytes.as_slice()
+    }
+}
+
+impl Write for SecretBuf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let needed = self.bytes.len + buf.len();
+        if needed > self.bytes.capacity {
+            let mut grown =
+                LockedBytes::with_capacity(std::cmp::max(needed, 2 * self.bytes.capacity));
+            grown.extend_from_slice(self.bytes.as_slice());
+            // the old allocation is wiped when dropped
+            self.bytes = grown;
+        }
+        self.bytes.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Memory for secrets of a fixed capacity, locked against being swapped out
+/// where possible and wiped when dropped.
+///
+/// It is allocated in whole pages of its own, so that unlocking it doesn't
+/// unlock pages that other secrets share.
+struct LockedBytes {
+    ptr: NonNull<u8>,
+    len: usize,
+    capacity: usize,
+    locked: bool,
+}
+
+// the memory is owned like that of a Vec
+unsafe impl Send for LockedBytes {}
+unsafe impl Sync for LockedBytes {}
+
+impl LockedBytes {
+    /// Allocates memory for at least `capacity` bytes, rounded up to whole
+    /// pages.  Nothing is allocated for a capacity of 0.
+    fn with_capacity(capacity: usize) -> Self {
+        if capacity == 0 {
+            return Self::default();
+        }
+        let layout = page_layout(capacity);
+        let ptr = match NonNull::new(unsafe { alloc::alloc(layout) }) {
+            Some(ptr) => ptr,
+            None => alloc::handle_alloc_error(layout),
+        };
+        let locked = lock(ptr.as_ptr(), layout.size());
+        Self {
+            ptr,
+            len: 0,
+            capacity: layout.size(),
+            locked,
+        }
+    }
+
+    /// Appends `buf`, which has to fit into the capacity.
+    fn extend_from_slice(&mut self, buf: &[u8]) {
+        assert!(self.len + buf.len() <= self.capacity);
+        unsafe {
+            std::ptr::copy_nonoverlapping(buf.as_ptr(), self.ptr.as_ptr().add(self.len), buf.len());
+        }
+        self.len += buf.len();
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Default for LockedBytes {
+    fn default() -> Self {
+        Self {
+            ptr: NonNull::dangling(),
+            len: 0,
+            capacity: 0,
+            locked: false,
+        }
+    }
+}
+
+impl Drop for LockedBytes {
+    fn drop(&mut self) {
+        if self.capacity == 0 {
+            return;
+        }
+        unsafe {
+            std::slice::from_raw_parts_mut(self.ptr.as_ptr(), self.capacity).zeroize();
+        }
+        if self.locked {
+            unlock(self.ptr.as_ptr(), self.capacity);
+        }
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), page_layout(self.capacity)) };
+    }
+}
+
+/// Returns the layout of whole, page-aligned pages for `size` bytes.
+fn page_layout(size: usize) -> Layout {
+    let page_size = page_size();
+    let size = size.div_ceil(page_size) * page_size;
+    Layout::from_size_align(size, page_size).unwrap()
+}
+
+#[cfg(unix)]
+fn page_size() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+#[cfg(not(unix))]
+fn page_size() -> usize {
+    4096
+}
+
+#[cfg(unix)]
+fn lock(ptr: *mut u8, size: usize) -> bool {
+    unsafe { libc::mlock(ptr as *const libc::c_void, size) == 0 }
+}
+
+#[cfg(unix)]
+fn unlock(ptr: *mut u8, size: usize) {
+    unsafe {
+        libc::munlock(ptr as *const libc::c_void, size);
+    }
+}
+
+#[cfg(not(unix))]
+fn lock(_ptr: *mut u8, _size: usize) -> bool {
+    false
+}
+
+#[cfg(not(unix))]
+fn unlock(_ptr: *mut u8, _size: usize) {}
+
+#[cfg(test)]
+mod test {
+    use super::{page_size, Secret, SecretBuf};
+    use std::io::Write;
+
+    #[test]
+    fn test_secret() {
+        let secret = Secret::new("passphrase".to_string());
+        assert_eq!(secret.expose(), b"passphrase");
+        assert_eq!(secret.clone().expose_str(), "passphrase");
+
+        // secrets don't share pages, which are locked and unlocked as a whole
+        let other = Secret::new("other".to_string());
+        assert_eq!(secret.expose().as_ptr() as usize % page_size(), 0);
+        assert_eq!(other.expose().as_ptr() as usize % page_size(), 0);
+        assert_eq!(Secret::new(String::new()).expose(), b"");
+    }
+
+    #[test]
+    fn test_secret_buf() {
+        let mut buf = SecretBuf::new();
+        for _ in 0..100 {
+            buf.write_all(b"0123456789").unwrap();
+        }
+        assert_eq!(buf.as_slice().len(), 1000);
+        assert!(buf.as_slice().starts_with(b"01234567890123"));
+        assert_eq!(buf.as_slice().as_ptr() as usize % page_size(), 0);
+    }
+}
@@ -1,20 +1,65 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs::File;
 use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 use serde::{Deserialize, Serialize};
 
-use crate::filesync::FileStatus;
+use crate::conflict::Conflict;
+use crate::filesync::{FileMeta, FileStatus};
 use crate::syncentity::SyncEntity;
+use crate::{fileutils, merge, names};
 
-const DB_VERSION: u32 = 1;
+const DB_VERSION: u32 = 3;
+
+/// File name of the renames of a migration of the names in the gpg root,
+/// while they are being performed.  Lives next to the database.
+const RENAMES_FILENAME: &str = ".gpgsync-renames";
+
+/// Path of the backup of the database at `db_path`, the previously saved version.
+pub fn backup_path(db_path: &Path) -> PathBuf {
+    let mut name = db_path.file_name().unwrap().to_owned();
+    name.push(".bak");
+    db_path.with_file_name(name)
+}
+
+/// Keeps the database at `fp`, if there is one, as the backup.  It is
+/// hard-linked, or copied where that isn't supported, so that it stays in
+/// place until the new database is renamed over it.
+fn keep_backup(fp: &Path) -> io::Result<()> {
+    if !fp.exists() {
+        return Ok(());
+    }
+    let backup = backup_path(fp);
+    match std::fs::remove_file(&backup) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        result => result?,
+    }
+    if std::fs::hard_link(fp, &backup).is_err() {
+        std::fs::copy(fp, &backup)?;
+    }
+    Ok(())
+}
 
 #[derive(Serialize, Deserialize)]
 pub struct SyncDb {
     gpg_root: PathBuf,
     db: HashMap<PathBuf, (FileStatus, FileStatus)>,
     db_version: u32,
+    /// Relative paths of the files already re-encrypted by an unfinished rekey.
+    #[serde(default)]
+    rekeyed: Option<HashSet<PathBuf>>,
+    /// Conflicts that the user has not resolved yet, by relative path.
+    #[serde(default)]
+    conflicts: HashMap<PathBuf, Conflict>,
+    /// Relative paths of the directories that existed on both sides after
+    /// the last sync.
+    #[serde(default)]
+    dirs: HashSet<PathBuf>,
+    /// Whether the names in the gpg root are encrypted.
+    #[serde(default)]
+    encrypted_names: bool,
 }
 
 impl SyncDb {
@@ -25,9 +70,18 @@ impl SyncDb {
             gpg_root: gpg_root.to_owned(),
             db: HashMap::new(),
             db_version: DB_VERSION,
+            rekeyed: None,
+            conflicts: HashMap::new(),
+            dirs: HashSet::new(),
+            encrypted_names: false,
         }
     }
 
+    /// Returns whether nothing was synced yet.
+    pub fn is_empty(&self) -> bool {
+        self.db.is_empty() && self.dirs.is_empty() && self.conflicts.is_empty()
+    }
+
     pub fn get_file_status(&self, se: &SyncEntity) -> (FileStatus, FileStatus) {
         self.db
             .get(se.rel_without_gpg())
@@ -43,22 +97,151 @@ impl SyncDb {
         self.db
             .insert(se.rel_without_gpg().clone(), (plain_status, gpg_status));
     }
-    pub fn save_db(&self, fp: &PathBuf) {
+    /// Moves the statuses and the conflict of `from` to `to`.
+    pub fn rename(&mut self, from: &SyncEntity, to: &SyncEntity) {
+        if let Some(statuses) = self.db.remove(from.rel_without_gpg()) {
+            self.db.insert(to.rel_without_gpg().clone(), statuses);
+        }
+        if let Some(conflict) = self.conflicts.remove(from.rel_without_gpg()) {
+            self.conflicts
+                .insert(to.rel_without_gpg().clone(), conflict);
+        }
+    }
+    /// Returns the relative paths of the entities below the directory
+    /// `prefix` that exist on either side according to the database.
+    pub fn entities_under(&self, prefix: &Path) -> Vec<PathBuf> {
+        self.db
+            .iter()
+            .filter(|(rel, statuses)| {
+                rel.starts_with(prefix)
+                    && *rel != prefix
+                    && **statuses != (FileStatus::Nonexistent, FileStatus::Nonexistent)
+            })
+            .map(|(rel, _)| rel.clone())
+            .collect()
+    }
+    /// Returns whether the database has a record of anything below the
+    /// directory `prefix`, even if it doesn't exist anymore.
+    pub fn has_entities_under(&self, prefix: &Path) -> bool {
+        self.db
+            .keys()
+            .any(|rel| rel.starts_with(prefix) && rel != prefix)
+    }
+    /// Moves the statuses and conflicts of everything below the directory
+    /// `from` to below `to`, and the directory itself.
+    pub fn rename_dir(&mut self, from: &Path, to: &Path) {
+        let moved = |rel: &PathBuf| match rel.strip_prefix(from) {
+            Ok(r) if r != Path::new("") => to.join(r),
+            _ => rel.clone(),
+        };
+        self.db = self
+            .db
+            .drain()
+            .map(|(rel, statuses)| (moved(&rel), statuses))
+            .collect();
+        self.dirs = self
+            .dirs
+            .drain()
+            .map(|rel| match rel.strip_prefix(from) {
+                Ok(r) => to.join(r).components().collect(),
+                Err(_) => rel,
+            })
+            .collect();
+        self.conflicts = self
+            .conflicts
+            .drain()
+            .map(|(rel, mut conflict)| {
+                conflict.copy = moved(&conflict.copy);
+                (moved(&rel), conflict)
+            })
+            .collect();
+    }
+    pub fn is_dir_synced(&self, rel: &Path) -> bool {
+        self.dirs.contains(rel)
+    }
+    pub fn set_dir_synced(&mut self, rel: &Path, synced: bool) {
+        if synced {
+            self.dirs.insert(rel.to_path_buf());
+        } else {
+            self.dirs.remove(rel);
+        }
+    }
+    /// Returns the relative paths of the synced directories below `prefix`.
+    pub fn dirs_under(&self, prefix: &Path) -> Vec<PathBuf> {
+        self.dirs
+            .iter()
+            .filter(|rel| rel.starts_with(prefix) && *rel != prefix)
+            .cloned()
+            .collect()
+    }
+    pub fn synced_dirs(&self) -> impl Iterator<Item = &PathBuf> {
+        self.dirs.iter()
+    }
+    pub fn conflict(&self, se: &SyncEntity) -> Option<&Conflict> {
+        self.conflicts.get(se.rel_without_gpg())
+    }
+    pub fn set_conflict(&mut self, se: &SyncEntity, conflict: Conflict) {
+        self.conflicts
+            .insert(se.rel_without_gpg().clone(), conflict);
+    }
+    pub fn clear_conflict(&mut self, se: &SyncEntity) -> Option<Conflict> {
+        self.conflicts.remove(se.rel_without_gpg())
+    }
+    /// Returns the relative paths of all conflicted entities.
+    pub fn conflicted(&self) -> impl Iterator<Item = &PathBuf> {
+        self.conflicts.keys()
+    }
+    /// Returns the relative path of the entity whose conflict copy is at `rel`.
+    pub fn conflicted_by_copy(&self, rel: &Path) -> Option<&PathBuf> {
+        self.conflicts
+            .iter()
+            .find(|(_, c)| c.copy == rel)
+            .map(|(p, _)| p)
+    }
+    /// Starts recording the progress of a rekey, unless an unfinished one is
+    /// to be resumed.
+    pub fn begin_rekey(&mut self) {
+        if self.rekeyed.is_none() {
+            self.rekeyed = Some(HashSet::new());
+        }
+    }
+    pub fn is_rekeyed(&self, se: &SyncEntity) -> bool {
+        self.rekeyed
+            .as_ref()
+            .is_some_and(|r| r.contains(se.rel_without_gpg()))
+    }
+    pub fn set_rekeyed(&mut self, se: &SyncEntity) {
+        if let Some(r) = &mut self.rekeyed {
+            r.insert(se.rel_without_gpg().clone());
+        }
+    }
+    pub fn finish_rekey(&mut self) {
+        self.rekeyed = None;
+    }
+    /// Saves the database to `fp`, keeping the previous one as a backup.
+    ///
+    /// The database is written atomically, so that a crash leaves either the
+    /// old or the new database behind.  The previous one is only turned into
+    /// the backup once the new one is written, so that there is a database at
+    /// `fp` at all times.
+    pub fn save_db(&self, fp: &Path) -> io::Result<()> {
         // TODO also persist gpg_path to disk to make sure that the database is for the correct sync target
 
-        let mut f = std::fs::OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&fp)
-            .unwrap();
-
-        let serialized = serde_json::to_string(&self).unwrap();
+        let serialized = serde_json::to_string(&self)?;
 
-        f.write_all(&serialized.as_bytes()).unwrap();
+        fileutils::write_atomically(fp, |f| {
+            f.write_all(serialized.as_bytes())?;
+            keep_backup(fp)
+        })
     }
 
-    pub fn load_db(fp: &PathBuf) -> Option<Self> {
+    /// Loads the database from `fp`.  Returns `None` if there is no database,
+    /// an error of kind `InvalidData` if it is corrupt and one of kind
+    /// `Unsupported` if it has an unknown version, e. g. from a newer
+    /// gpgsync.  A database of an older version is migrated, for which the
+    /// gpg files are looked up with `extension`, except for the names in the
+    /// gpg root, see `migrate_names()`.
+    pub fn load_db(fp: &PathBuf, extension: &str) -> io::Result<Option<Self>> {
         // TODO also read gpg_path from disk and refuse to load if existing db is for a different sync target
         // TODO this function would then load "existing sync configuration", not just the db
 
@@ -67,24 +250,447 @@ impl SyncDb {
                 println!("loading existing db from {:?}", fp);
 
                 let mut s = String::new();
-                f.read_to_string(&mut s).unwrap();
-                dbg!(&s);
-                let deserialized: SyncDb = serde_json::from_str(&s).unwrap();
+                f.read_to_string(&mut s)?;
+                let value: serde_json::Value = serde_json::from_str(&s)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
 
                 // make sure the db schema is correct
-                assert!(deserialized.db_version == DB_VERSION);
+                let plain_root = fp.parent().unwrap();
+                let version = value["db_version"].as_u64();
+                let deserialized: SyncDb = match version {
+                    Some(1) => {
+                        println!("migrating the database from version 1");
+                        serde_json::from_value::<SyncDbV1>(value)
+                            .map(|v1| v1.migrate(plain_root, extension))
+                    }
+                    // only the names in the gpg root differ from version 3
+                    Some(2) => serde_json::from_value(value),
+                    Some(v) if v == u64::from(DB_VERSION) => serde_json::from_value(value),
+                    v => {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Unsupported,
+                            format!("unsupported database version {:?}", v),
+                        ))
+                    }
+                }
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
 
-                Some(deserialized)
+                Ok(Some(deserialized))
             }
             Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
                 println!("no db found yet at {:?}", fp);
-                None
+                Ok(None)
             }
-            Err(e) => panic!("{:?}", e),
+            Err(e) => Err(e),
         }
     }
 
     pub fn gpg_root(&self) -> &Path {
         &self.gpg_root
     }
+
+    /// Renames the gpg files and directories of the database loaded for `fp`,
+    /// possibly from its backup, if they still have the names of database
+    /// version 2, see `unescaped_renames()`.  The migrated database is saved
+    /// then, as the backup as well.
+    ///
+    /// The renames are recorded before the first one is performed, and a
+    /// recorded migration is finished before anything else, even if the
+    /// database was saved as migrated already.  The renames are only computed
+    /// once, because after some of them were performed, a file could be taken
+    /// for another one that was renamed to its old name.
+    pub fn migrate_names(&mut self, fp: &Path, extension: &str) -> io::Result<()> {
+        let plain_root = fp.parent().unwrap();
+        let renames_path = plain_root.join(RENAMES_FILENAME);
+        let renames: Vec<(PathBuf, PathBuf)> = match std::fs::read(&renames_path) {
+            Ok(s) => {
+                println!("finishing the interrupted migration of the names in the gpg root");
+                serde_json::from_slice(&s)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound && self.db_version < 3 => {
+                println!("escaping the names in the gpg root");
+                let renames = self.unescaped_renames(plain_root, extension);
+                let serialized = serde_json::to_string(&renames)?;
+                fileutils::write_atomically(&renames_path, |f| f.write_all(serialized.as_bytes()))?;
+                renames
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+
+        for (old, new) in &renames {
+            // an interrupted migration performed it already
+            if std::fs::symlink_metadata(old).is_err() {
+                continue;
+            }
+            if std::fs::symlink_metadata(new).is_ok() {
+                println!("not renaming {:?}, {:?} exists", old, new);
+                continue;
+            }
+            println!("renaming {:?} to {:?}", old, new);
+            std::fs::rename(old, new)?;
+        }
+
+        self.db_version = DB_VERSION;
+        self.save_db(fp)?;
+        // the backup of version 2 would otherwise be migrated again
+        keep_backup(fp)?;
+        std::fs::remove_file(&renames_path)
+    }
+
+    /// Returns the renames of the gpg files and directories of all entities,
+    /// and of their base versions, from the names of database version 2 to
+    /// the escaped ones of `names::gpg_name()`, in the order in which they
+    /// have to be performed.  Only names that exist are renamed.
+    ///
+    /// Version 2 appended the extension to every file name and kept the names
+    /// of directories, so the file `backup.gpg` was `backup.gpg.gpg` and the
+    /// directory `backup.gpg` kept its name.  Only the last component of a path
+    /// is renamed at a time, files first and then directories from the bottom
+    /// up, so that the parents still have their old names.  Longer names go
+    /// first, so that `backup.gpg_` is out of the way before `backup.gpg` is
+    /// renamed to it.
+    fn unescaped_renames(&self, plain_root: &Path, extension: &str) -> Vec<(PathBuf, PathBuf)> {
+        let mut renames = Vec::new();
+        if self.encrypted_names {
+            return renames;
+        }
+
+        let mut dirs = self.dirs.clone();
+        for rel in self.db.keys() {
+            dirs.extend(
+                rel.ancestors()
+                    .skip(1)
+                    .filter(|a| *a != Path::new(""))
+                    .map(Path::to_path_buf),
+            );
+        }
+        let mut entities = self
+            .db
+            .keys()
+            .map(|rel| (rel, true))
+            .chain(dirs.iter().map(|rel| (rel, false)))
+            .collect::<Vec<_>>();
+        entities.sort_by_key(|(rel, is_file)| {
+            (
+                !is_file,
+                std::cmp::Reverse(rel.components().count()),
+                std::cmp::Reverse(rel.as_os_str().len()),
+            )
+        });
+
+        let roots = [self.gpg_root.clone(), plain_root.join(merge::BASE_DIRNAME)];
+        for root in &roots {
+            for (rel, is_file) in &entities {
+                let name = match rel.file_name() {
+                    Some(name) => name,
+                    None => continue,
+                };
+                let mut old_name = name.to_owned();
+                if *is_file {
+                    old_name.push(".");
+                    old_name.push(extension);
+                }
+                let new_name = names::gpg_name(name, extension, *is_file);
+                if old_name == new_name {
+                    continue;
+                }
+
+                let dir = root.join(rel.parent().unwrap());
+                let (old, new) = (dir.join(old_name), dir.join(new_name));
+                if std::fs::symlink_metadata(&old).is_ok() {
+                    renames.push((old, new));
+                }
+            }
+        }
+
+        renames
+    }
+
+    pub fn encrypted_names(&self) -> bool {
+        self.encrypted_names
+    }
+    pub fn set_encrypted_names(&mut self, encrypted_names: bool) {
+        self.encrypted_names = encrypted_names;
+    }
+}
+
+/// A file status of database version 1, which only recorded the mtime.
+#[derive(Deserialize)]
+enum FileStatusV1 {
+    Nonexistent,
+    Existent(SystemTime),
+}
+
+impl FileStatusV1 {
+    /// Converts the status of the file at `p`.
+    ///
+    /// If the file still has the recorded mtime, its current size and inode
+    /// are taken over, so that it isn't seen as modified.  Otherwise the
+    /// status keeps only the old mtime and the file counts as modified.  The
+    /// hash is computed when it is first needed.
+    fn migrate(self, p: &PathBuf) -> FileStatus {
+        let mtime = match self {
+            FileStatusV1::Nonexistent => return FileStatus::Nonexistent,
+            FileStatusV1::Existent(mtime) => mtime,
+        };
+        match fileutils::file_status(p) {
+            Ok(FileStatus::Existent(meta)) if meta.mtime == mtime => FileStatus::Existent(meta),
+            _ => FileStatus::Existent(FileMeta {
+                size: 0,
+                mtime,
+                inode: 0,
+                hash: None,
+                checked: SystemTime::now(),
+            }),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct ConflictV1 {
+    copy: PathBuf,
+    gpg_status: FileStatusV1,
+}
+
+#[derive(Deserialize)]
+struct SyncDbV1 {
+    gpg_root: PathBuf,
+    db: HashMap<PathBuf, (FileStatusV1, FileStatusV1)>,
+    #[serde(default)]
+    rekeyed: Option<HashSet<PathBuf>>,
+    #[serde(default)]
+    conflicts: HashMap<PathBuf, ConflictV1>,
+}
+
+impl SyncDbV1 {
+    fn migrate(self, plain_root: &Path, extension: &str) -> SyncDb {
+        let gpg_root = self.gpg_root;
+        let gpg_path = |rel: &PathBuf| {
+            let mut name = gpg_root.join(rel).into_os_string();
+            name.push(".");
+            name.push(extension);
+            PathBuf::from(name)
+        };
+
+        let db = self
+            .db
+            .into_iter()
+            .map(|(rel, (plain_status, gpg_status))| {
+                let statuses = (
+                    plain_status.migrate(&plain_root.join(&rel)),
+                    gpg_status.migrate(&gpg_path(&rel)),
+                );
+                (rel, statuses)
+            })
+            .collect();
+        let conflicts = self
+            .conflicts
+            .into_iter()
+            .map(|(rel, c)| {
+                let conflict = Conflict {
+                    copy: c.copy,
+                    gpg_status: c.gpg_status.migrate(&gpg_path(&rel)),
+                };
+                (rel, conflict)
+            })
+            .collect();
+
+        SyncDb {
+            gpg_root,
+            db,
+            // the names in the gpg root are escaped by `migrate_names()`
+            db_version: 2,
+            rekeyed: self.rekeyed,
+            conflicts,
+            dirs: HashSet::new(),
+            encrypted_names: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{backup_path, SyncDb, DB_VERSION, RENAMES_FILENAME};
+    use crate::filesync::{FileMeta, FileStatus};
+    use crate::fileutils;
+    use crate::syncentity::{SyncEntity, SyncRoots};
+    use std::io;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+    use std::time::UNIX_EPOCH;
+
+    #[test]
+    fn test_save_and_recover() {
+        let dir = tempfile::tempdir().unwrap();
+        let db_path = dir.path().join("db");
+
+        assert!(SyncDb::load_db(&db_path, "gpg").unwrap().is_none());
+
+        SyncDb::new(Path::new("/first")).save_db(&db_path).unwrap();
+        SyncDb::new(Path::new("/second")).save_db(&db_path).unwrap();
+        assert_eq!(
+            SyncDb::load_db(&db_path, "gpg")
+                .unwrap()
+                .unwrap()
+                .gpg_root(),
+            Path::new("/second")
+        );
+
+        // a database that was cut off while writing is detected
+        let contents = std::fs::read(&db_path).unwrap();
+        std::fs::write(&db_path, &contents[..contents.len() / 2]).unwrap();
+        assert_eq!(
+            SyncDb::load_db(&db_path, "gpg").err().unwrap().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // the backup holds the previous version
+        assert_eq!(backup_path(&db_path), dir.path().join("db.bak"));
+        assert_eq!(
+            SyncDb::load_db(&backup_path(&db_path), "gpg")
+                .unwrap()
+                .unwrap()
+                .gpg_root(),
+            Path::new("/first")
+        );
+    }
+
+    #[test]
+    fn test_migrate_v1() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        std::fs::write(dir.join("unchanged"), b"same").unwrap();
+        std::fs::write(dir.join("modified"), b"new").unwrap();
+        let mtime = std::fs::metadata(dir.join("unchanged"))
+            .unwrap()
+            .modified()
+            .unwrap();
+
+        let db_path = dir.join("db");
+        let v1 = serde_json::json!({
+            "gpg_root": "/gpg",
+            "db": {
+                "unchanged": [{"Existent": mtime}, "Nonexistent"],
+                "modified": [{"Existent": UNIX_EPOCH}, "Nonexistent"],
+            },
+            "db_version": 1,
+        });
+        std::fs::write(&db_path, v1.to_string()).unwrap();
+
+        let db = SyncDb::load_db(&db_path, "gpg").unwrap().unwrap();
+        let unchanged = &db.db[Path::new("unchanged")].0;
+        assert!(unchanged.same_metadata(&fileutils::file_status(&dir.join("unchanged")).unwrap()));
+        let modified = &db.db[Path::new("modified")].0;
+        assert!(!modified.same_metadata(&fileutils::file_status(&dir.join("modified")).unwrap()));
+        assert_eq!(db.db[Path::new("modified")].1, FileStatus::Nonexistent);
+    }
+
+    #[test]
+    fn test_rename_dir() {
+        let roots = Arc::new(SyncRoots {
+            plain_root: PathBuf::from("/plain"),
+            gpg_root: PathBuf::from("/gpg"),
+            extension: "gpg",
+            name_cipher: None,
+        });
+        let se = |rel: &str| SyncEntity::from_rel(Path::new(rel), &roots);
+        let existent = FileStatus::Existent(FileMeta {
+            size: 1,
+            mtime: UNIX_EPOCH,
+            inode: 1,
+            hash: None,
+            checked: UNIX_EPOCH,
+        });
+
+        let mut db = SyncDb::new(Path::new("/gpg"));
+        for rel in &["d/a", "d/sub/b", "dd/c", "d"] {
+            db.set_file_status(&se(rel), existent.clone(), existent.clone());
+        }
+        db.set_file_status(
+            &se("d/gone"),
+            FileStatus::Nonexistent,
+            FileStatus::Nonexistent,
+        );
+
+        let mut under = db.entities_under(Path::new("d"));
+        under.sort();
+        assert_eq!(under, vec![PathBuf::from("d/a"), PathBuf::from("d/sub/b")]);
+        assert!(db.has_entities_under(Path::new("d")));
+
+        db.rename_dir(Path::new("d"), Path::new("e/f"));
+        assert!(!db.has_entities_under(Path::new("d")));
+        assert_eq!(db.get_file_status(&se("e/f/sub/b")).0, existent);
+        assert_eq!(db.get_file_status(&se("dd/c")).0, existent);
+        assert_eq!(db.get_file_status(&se("d")).0, existent);
+    }
+
+    #[test]
+    fn test_migrate_names_interrupted() {
+        let tmp = tempfile::tempdir().unwrap();
+        let roots = Arc::new(SyncRoots {
+            plain_root: tmp.path().join("plain"),
+            gpg_root: tmp.path().join("gpg"),
+            extension: "gpg",
+            name_cipher: None,
+        });
+        let (plain_root, gpg_root) = (&roots.plain_root, &roots.gpg_root);
+        std::fs::create_dir_all(plain_root).unwrap();
+        std::fs::create_dir_all(gpg_root.join("x.gpg")).unwrap();
+        std::fs::write(gpg_root.join("backup.gpg.gpg"), b"b").unwrap();
+        std::fs::write(gpg_root.join("backup.gpg_.gpg"), b"b_").unwrap();
+        std::fs::write(gpg_root.join("x.gpg/notes.txt.gpg"), b"n").unwrap();
+
+        let mut db = SyncDb::new(gpg_root);
+        db.db_version = 2;
+        for rel in &["backup.gpg", "backup.gpg_", "x.gpg/notes.txt"] {
+            db.set_file_status(
+                &SyncEntity::from_rel(Path::new(rel), &roots),
+                FileStatus::Nonexistent,
+                FileStatus::Nonexistent,
+            );
+        }
+        let db_path = plain_root.join("db");
+        db.save_db(&db_path).unwrap();
+
+        // crash after the first two renames were performed
+        let renames = db.unescaped_renames(plain_root, "gpg");
+        assert_eq!(renames.len(), 3);
+        std::fs::write(
+            plain_root.join(RENAMES_FILENAME),
+            serde_json::to_string(&renames).unwrap(),
+        )
+        .unwrap();
+        for (old, new) in &renames[..2] {
+            std::fs::rename(old, new).unwrap();
+        }
+
+        // `backup.gpg_.gpg` is not taken for the file of `backup.gpg_` again
+        let mut db = SyncDb::load_db(&db_path, "gpg").unwrap().unwrap();
+        assert_eq!(db.db_version, 2);
+        db.migrate_names(&db_path, "gpg").unwrap();
+        assert_eq!(
+            std::fs::read(gpg_root.join("backup.gpg_.gpg")).unwrap(),
+            b"b"
+        );
+        assert_eq!(
+            std::fs::read(gpg_root.join("backup.gpg__.gpg")).unwrap(),
+            b"b_"
+        );
+        assert!(!gpg_root.join("backup.gpg___.gpg").exists());
+        assert!(gpg_root.join("x.gpg_/notes.txt.gpg").exists());
+        assert!(!plain_root.join(RENAMES_FILENAME).exists());
+
+        // neither the database nor its backup are migrated again
+        for fp in &[db_path.clone(), backup_path(&db_path)] {
+            let mut db = SyncDb::load_db(fp, "gpg").unwrap().unwrap();
+            assert_eq!(db.db_version, DB_VERSION);
+            db.migrate_names(&db_path, "gpg").unwrap();
+        }
+        assert_eq!(
+            std::fs::read(gpg_root.join("backup.gpg_.gpg")).unwrap(),
+            b"b"
+        );
+    }
 }
@@ -1,73 +1,221 @@
+use std::hash::{Hash, Hasher};
+use std::io;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::name_cipher::NameCipher;
+use crate::names;
+use crate::policy::{EncryptionPolicy, PolicySet};
+
+/// The two directories that are kept in sync, shared by all sync entities.
+#[derive(Debug)]
+pub struct SyncRoots {
+    pub plain_root: PathBuf,
+    pub gpg_root: PathBuf,
+    /// The extension of encrypted files, e. g. "gpg".
+    pub extension: &'static str,
+    /// Encrypts the names in the gpg root, if they are encrypted.
+    pub name_cipher: Option<NameCipher>,
+}
+
+impl SyncRoots {
+    /// Returns the path in the gpg root of the file or directory at `rel` in
+    /// the plain root.  Each name is mapped by `names::gpg_name`, or encrypted
+    /// by the name cipher.
+    pub fn gpg_path(&self, rel: &Path, is_file: bool) -> PathBuf {
+        let last = rel.iter().count();
+        let mut p = self.gpg_root.clone();
+        for (i, name) in rel.iter().enumerate() {
+            let is_file = is_file && i + 1 == last;
+            p.push(match &self.name_cipher {
+                Some(name_cipher) => name_cipher.gpg_name(name, self.extension, is_file),
+                None => names::gpg_name(name, self.extension, is_file),
+            });
+        }
+        p
+    }
+
+    /// Stores the encrypted long names in the path of `rel` in the gpg root,
+    /// see `NameCipher::store_long_name()`.  Has to be called before the path
+    /// is created there.
+    pub fn store_long_names(&self, rel: &Path, is_file: bool) -> io::Result<()> {
+        if let Some(name_cipher) = &self.name_cipher {
+            let last = rel.iter().count();
+            for (i, name) in rel.iter().enumerate() {
+                name_cipher.store_long_name(name, self.extension, is_file && i + 1 == last)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the path relative to the plain root of the file or directory
+    /// at `gpg_path`, the reverse of `gpg_path()`.  Returns `None` if
+    /// `gpg_path` is not in the gpg root, or if it has a name that no name in
+    /// the plain root maps to.
+    pub fn plain_rel(&self, gpg_path: &Path, is_file: bool) -> Option<PathBuf> {
+        let rel = gpg_path.strip_prefix(&self.gpg_root).ok()?;
+        let last = rel.iter().count();
+        if is_file && last == 0 {
+            return None;
+        }
+        rel.iter()
+            .enumerate()
+            .map(|(i, name)| {
+                let is_file = is_file && i + 1 == last;
+                match &self.name_cipher {
+                    Some(name_cipher) => name_cipher.plain_name(name, self.extension, is_file),
+                    None => names::plain_name(name, self.extension, is_file),
+                }
+            })
+            .collect()
+    }
+}
 
 /// A sync entity represents up to two files by a relative path. It can exist unencrypted relative to the plain_root and
-/// encrypted (with .gpg extension) relative to the gpg_root.
-#[derive(Debug, PartialEq, Eq, Hash)]
-pub struct SyncEntity<'a> {
+/// encrypted (with the extension of the crypto backend, e. g. .gpg) relative to the gpg_root.  See `names` for how the
+/// names are mapped.
+///
+/// It owns a reference to its roots, so that it can be handed to other threads.  Sync entities are only ever
+/// compared with those of the same roots, so they are equal if their paths are.
+#[derive(Debug)]
+pub struct SyncEntity {
     rel_path_without_gpg: PathBuf,
-    plain_root: &'a PathBuf,
-    gpg_root: &'a PathBuf,
+    roots: Arc<SyncRoots>,
 }
 
-fn add_gpg_extension(p: &PathBuf) -> PathBuf {
-    let mut name = p.file_name().unwrap().to_owned();
-    name.push(".gpg");
-    p.parent().unwrap().join(&name)
+impl PartialEq for SyncEntity {
+    fn eq(&self, other: &Self) -> bool {
+        self.rel_path_without_gpg == other.rel_path_without_gpg
+    }
 }
 
-fn remove_gpg_extension(p: &Path) -> PathBuf {
-    p.parent()
-        .unwrap()
-        .join(p.file_stem().unwrap())
-        .to_path_buf()
+impl Eq for SyncEntity {}
+
+impl Hash for SyncEntity {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.rel_path_without_gpg.hash(state);
+    }
 }
 
-impl<'a> SyncEntity<'a> {
-    pub fn from_plain(
-        plain_path: &PathBuf,
-        plain_root: &'a PathBuf,
-        gpg_root: &'a PathBuf,
-    ) -> Self {
+impl SyncEntity {
+    pub fn from_plain(plain_path: &Path, roots: &Arc<SyncRoots>) -> Self {
         let relative_path_without_gpg = plain_path
-            .strip_prefix(&plain_root) // check that it is indeed a subpath
+            .strip_prefix(&roots.plain_root) // check that it is indeed a subpath
             .unwrap()
             .to_path_buf();
         Self {
             rel_path_without_gpg: relative_path_without_gpg,
-            plain_root,
-            gpg_root,
+            roots: roots.clone(),
         }
     }
-    pub fn from_gpg(gpg_path: &PathBuf, plain_root: &'a PathBuf, gpg_root: &'a PathBuf) -> Self {
-        let rel_path_without_gpg = remove_gpg_extension(&gpg_path)
-            .strip_prefix(&gpg_root) // check that it is indeed a subpath
-            .unwrap()
-            .to_path_buf();
+    /// Returns the entity of the encrypted file at `gpg_path`, or `None` if
+    /// no plain file is encrypted to that path.
+    pub fn from_gpg(gpg_path: &Path, roots: &Arc<SyncRoots>) -> Option<Self> {
+        let rel_path_without_gpg = roots.plain_rel(gpg_path, true)?;
 
-        Self {
+        Some(Self {
             rel_path_without_gpg,
-            plain_root,
-            gpg_root,
+            roots: roots.clone(),
+        })
+    }
+
+    /// Returns the entity at a path relative to the roots, without the extension of encrypted files.
+    pub fn from_rel(rel_path_without_gpg: &Path, roots: &Arc<SyncRoots>) -> Self {
+        Self {
+            rel_path_without_gpg: rel_path_without_gpg.to_path_buf(),
+            roots: roots.clone(),
         }
     }
 
     pub fn as_plain(&self) -> PathBuf {
-        self.plain_root.join(&self.rel_path_without_gpg)
+        self.roots.plain_root.join(&self.rel_path_without_gpg)
     }
 
     pub fn as_gpg(&self) -> PathBuf {
-        add_gpg_extension(&self.gpg_root.join(&self.rel_path_without_gpg))
+        self.roots.gpg_path(&self.rel_path_without_gpg, true)
+    }
+
+    /// Like `as_gpg()`, for creating the gpg file.  Stores the long names in
+    /// the path first.
+    pub fn as_gpg_to_create(&self) -> io::Result<PathBuf> {
+        self.roots
+            .store_long_names(&self.rel_path_without_gpg, true)?;
+        Ok(self.as_gpg())
     }
 
     pub fn rel_without_gpg(&self) -> &PathBuf {
         &self.rel_path_without_gpg
     }
+
+    pub fn roots(&self) -> &Arc<SyncRoots> {
+        &self.roots
+    }
+
+    /// Returns the encryption policy that applies to this entity.
+    pub fn policy<'p>(&self, policies: &'p PolicySet) -> &'p EncryptionPolicy {
+        policies.policy_for(&self.rel_path_without_gpg)
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use super::{SyncEntity, SyncRoots};
+    use proptest::prelude::*;
+    use std::path::{Path, PathBuf};
+    use std::sync::Arc;
+
+    fn roots() -> Arc<SyncRoots> {
+        Arc::new(SyncRoots {
+            plain_root: PathBuf::from("/plain"),
+            gpg_root: PathBuf::from("/gpg"),
+            extension: "gpg",
+            name_cipher: None,
+        })
+    }
+
+    /// Names of files and directories, biased towards the ones that need
+    /// escaping.
+    fn name() -> impl Strategy<Value = String> {
+        "[^/\\x00]{1,8}(\\.gpg)?_{0,2}(\\.gpg)?"
+            .prop_filter("not a path component", |n| n != "." && n != "..")
+    }
+
     #[test]
     fn test_representation() {
-        // TODO the from_* and as_* methods shall work correctly
+        let roots = roots();
+        let se = SyncEntity::from_plain(&PathBuf::from("/plain/a.gpg/b.gpg"), &roots);
+        assert_eq!(se.as_plain(), Path::new("/plain/a.gpg/b.gpg"));
+        assert_eq!(se.as_gpg(), Path::new("/gpg/a.gpg_/b.gpg_.gpg"));
+        assert_eq!(SyncEntity::from_gpg(&se.as_gpg(), &roots), Some(se));
+
+        assert_eq!(SyncEntity::from_gpg(Path::new("/gpg/a.txt"), &roots), None);
+        assert_eq!(
+            SyncEntity::from_gpg(Path::new("/gpg/a.gpg/b.txt.gpg"), &roots),
+            None
+        );
+        assert_eq!(
+            SyncEntity::from_gpg(Path::new("/elsewhere/b.txt.gpg"), &roots),
+            None
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn test_round_trip(names in prop::collection::vec(name(), 1..4)) {
+            let roots = roots();
+            let rel = names.iter().collect::<PathBuf>();
+            let se = SyncEntity::from_plain(&roots.plain_root.join(&rel), &roots);
+            prop_assert_eq!(SyncEntity::from_gpg(&se.as_gpg(), &roots), Some(se));
+            prop_assert_eq!(roots.plain_rel(&roots.gpg_path(&rel, false), false), Some(rel));
+        }
+
+        #[test]
+        fn test_reverse_round_trip(names in prop::collection::vec(name(), 1..4), is_file: bool) {
+            let roots = roots();
+            let gpg_path = roots.gpg_root.join(names.iter().collect::<PathBuf>());
+            if let Some(rel) = roots.plain_rel(&gpg_path, is_file) {
+                prop_assert_eq!(roots.gpg_path(&rel, is_file), gpg_path);
+            }
+        }
     }
 }